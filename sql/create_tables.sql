@@ -10,9 +10,16 @@ CREATE TABLE match_results (
     id TEXT NOT NULL PRIMARY KEY,
     player_1_score INTEGER,
     player_2_score INTEGER,
+    abandoned BOOLEAN NOT NULL DEFAULT FALSE,
     end_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP
 );
 
+CREATE TABLE players (
+    id TEXT NOT NULL PRIMARY KEY,
+    rating REAL NOT NULL DEFAULT 1000,
+    games_played INTEGER NOT NULL DEFAULT 0
+);
+
 -- INSERT INTO match (
 --     id,
 --     player_1_id,
@@ -17,7 +17,7 @@ impl Client {
         }
     }
 
-    fn play(&mut self) -> Move {
+    pub fn play(&mut self) -> Move {
         let next_move = self.strategy.make_move(&self.history);
         self.last_move = Some(next_move.clone());
         next_move
@@ -1,30 +1,30 @@
 use common::model::game::{Move, Outcome};
 
 pub struct Round {
-    my_move: Move,
-    their_move: Move,
-    outcome: Outcome,
+    pub my_move: Move,
+    pub their_move: Move,
+    pub outcome: Outcome,
 }
 pub trait Strategy {
-    fn make_move(&self, history: &Vec<Round>) -> Move;
+    fn make_move(&self, history: &[Round]) -> Move;
 }
 
 // Trivial strategies
 pub struct OnlyRock {}
 impl Strategy for OnlyRock {
-    fn make_move(&self, _: &Vec<Round>) -> Move {
+    fn make_move(&self, _: &[Round]) -> Move {
         Move::Rock
     }
 }
 pub struct OnlyPaper {}
 impl Strategy for OnlyPaper {
-    fn make_move(&self, _: &Vec<Round>) -> Move {
+    fn make_move(&self, _: &[Round]) -> Move {
         Move::Paper
     }
 }
 pub struct OnlyScissors {}
 impl Strategy for OnlyScissors {
-    fn make_move(&self, _: &Vec<Round>) -> Move {
+    fn make_move(&self, _: &[Round]) -> Move {
         Move::Scissors
     }
 }
@@ -32,7 +32,7 @@ impl Strategy for OnlyScissors {
 // Random
 pub struct RandomMove {}
 impl Strategy for RandomMove {
-    fn make_move(&self, _: &Vec<Round>) -> Move {
+    fn make_move(&self, _: &[Round]) -> Move {
         Move::Rock
     }
 }
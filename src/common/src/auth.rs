@@ -0,0 +1,195 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, RequestBuilder};
+use serde::Serialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::model::messages::Id;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// How long a session token issued by matchmaking stays valid
+pub const SESSION_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// How long a player has to use the join ticket they got with their match
+pub const JOIN_TICKET_TTL: Duration = Duration::from_secs(60);
+
+// Websocket close code sent when a client identifies itself with a bad session token.
+// 4000-4999 is reserved for applications.
+pub const INVALID_TOKEN_CLOSE_CODE: u16 = 4001;
+
+// Header carrying the signature of a request body sent from one server to another
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+// Environment variable holding the secret tokens are signed with. Matchmaking and every game
+// server must share it.
+pub const TOKEN_SECRET_ENV_VAR: &str = "SESSION_TOKEN_SECRET";
+
+// Read the token secret. Servers refuse to start without one, as any default would be public
+// and let anyone forge tokens.
+pub fn token_secret_from_env() -> Result<String, MissingTokenSecret> {
+    match std::env::var(TOKEN_SECRET_ENV_VAR) {
+        Ok(secret) if !secret.is_empty() => Ok(secret),
+        _ => Err(MissingTokenSecret),
+    }
+}
+
+// TOKEN_SECRET_ENV_VAR is unset or empty
+#[derive(Debug)]
+pub struct MissingTokenSecret;
+
+impl fmt::Display for MissingTokenSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} must be set to the secret shared by matchmaking and the game servers",
+            TOKEN_SECRET_ENV_VAR
+        )
+    }
+}
+
+impl std::error::Error for MissingTokenSecret {}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "malformed"),
+            TokenError::BadSignature => write!(f, "bad signature"),
+            TokenError::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+// Session token proving the holder is `user_id`, formatted as `<user_id>.<expiry>.<signature>`
+pub fn issue_token(secret: &[u8], user_id: Id, ttl: Duration) -> String {
+    seal(secret, &[user_id], ttl)
+}
+
+// Check a token's signature and expiry, returning the user it was issued to
+pub fn verify_token(secret: &[u8], token: &str) -> Result<Id, TokenError> {
+    match open(secret, token)?[..] {
+        [user_id] => Ok(user_id),
+        _ => Err(TokenError::Malformed),
+    }
+}
+
+// Permission for one user to take their seat in one game, handed out by matchmaking
+#[derive(Debug, PartialEq)]
+pub struct JoinTicket {
+    pub game_id: Id,
+    pub user_id: Id,
+    // Unique per ticket, so the game server can refuse a ticket it has already seen
+    pub nonce: Id,
+}
+
+// Join ticket formatted as `<game_id>.<user_id>.<nonce>.<expiry>.<signature>`
+pub fn issue_join_ticket(secret: &[u8], game_id: Id, user_id: Id, ttl: Duration) -> String {
+    seal(secret, &[game_id, user_id, Id::new()], ttl)
+}
+
+// Check a join ticket's signature and expiry. Whether it's been used is up to the caller.
+pub fn verify_join_ticket(secret: &[u8], ticket: &str) -> Result<JoinTicket, TokenError> {
+    match open(secret, ticket)?[..] {
+        [game_id, user_id, nonce] => Ok(JoinTicket {
+            game_id,
+            user_id,
+            nonce,
+        }),
+        _ => Err(TokenError::Malformed),
+    }
+}
+
+// Signature of a request body, proving it was sent by a server holding the secret
+pub fn sign_body(secret: &[u8], body: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(mac(secret, body).finalize().into_bytes())
+}
+
+// Check that a request body was signed with the secret
+pub fn verify_body(secret: &[u8], body: &[u8], signature: &str) -> Result<(), TokenError> {
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+    mac(secret, body)
+        .verify_slice(&signature)
+        .map_err(|_| TokenError::BadSignature)
+}
+
+// Send `body` as JSON, signed with the secret
+pub fn signed_json(
+    request: RequestBuilder,
+    secret: &[u8],
+    body: &impl Serialize,
+) -> RequestBuilder {
+    match serde_json::to_vec(body) {
+        Ok(body) => request
+            .header(SIGNATURE_HEADER, sign_body(secret, &body))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body),
+        // Leave reporting the error to reqwest when the request is sent
+        Err(_) => request.json(body),
+    }
+}
+
+// Join ids and an expiry in seconds since the epoch with '.', followed by an HMAC-SHA256 of them
+fn seal(secret: &[u8], ids: &[Id], ttl: Duration) -> String {
+    let expires_at = unix_time(SystemTime::now() + ttl);
+    let mut payload = String::new();
+    for id in ids {
+        payload += &format!("{}.", id);
+    }
+    payload += &expires_at.to_string();
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, payload.as_bytes()).finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+// Check a sealed value's signature and expiry, returning its ids
+fn open(secret: &[u8], sealed: &str) -> Result<Vec<Id>, TokenError> {
+    let (payload, signature) = sealed.rsplit_once('.').ok_or(TokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+
+    // Signature first, so an expired token can't be used to probe for valid ones
+    mac(secret, payload.as_bytes())
+        .verify_slice(&signature)
+        .map_err(|_| TokenError::BadSignature)?;
+
+    let (ids, expires_at) = payload.rsplit_once('.').ok_or(TokenError::Malformed)?;
+    let expires_at: u64 = expires_at.parse().map_err(|_| TokenError::Malformed)?;
+    if unix_time(SystemTime::now()) >= expires_at {
+        return Err(TokenError::Expired);
+    }
+    ids.split('.')
+        .map(|id| {
+            Uuid::parse_str(id)
+                .map(Id)
+                .map_err(|_| TokenError::Malformed)
+        })
+        .collect()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .expect("Clock is before the epoch")
+        .as_secs()
+}
+
+fn mac(secret: &[u8], payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac
+}
@@ -1,3 +1,4 @@
+pub mod auth;
 pub mod message;
 pub mod model;
 pub mod utility;
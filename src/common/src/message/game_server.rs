@@ -1,4 +1,7 @@
-use crate::model::game::{Move, Outcome};
+use crate::{
+    model::game::{Move, Outcome},
+    websocket::ClientError,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -11,7 +14,8 @@ pub struct RoundResultResponse {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum ClientRequest {
-    JoinGame,
+    // Take a seat in the game, with the single-use ticket matchmaking sent alongside the match
+    JoinGame { ticket: String },
     Move { value: Move },
 }
 
@@ -19,11 +23,64 @@ pub enum ClientRequest {
 #[serde(tag = "type")]
 pub enum ClientResponse {
     GameJoined,
-    PendingMove,
+    // Prompt for the next move. Round is 1-indexed, and wins are counted from the recipient's view.
+    PendingMove {
+        round: u32,
+        wins: u8,
+        opponent_wins: u8,
+    },
     RoundResult(RoundResultResponse),
+    // A player didn't move before the deadline. Result is the round's outcome for the recipient.
+    MoveTimeout {
+        result: Outcome,
+        missed: bool,
+        opponent_missed: bool,
+    },
     MatchResult {
         result: Outcome,
         wins: u8,
-        total: u8,
+        total: u32,
+    },
+    // Rejoined a game in progress. Fields match PendingMove, plus whether a move is still owed.
+    Reconnected {
+        round: u32,
+        wins: u8,
+        opponent_wins: u8,
+        move_pending: bool,
+    },
+    // Game ended before it started. The player should rejoin the queue.
+    GameCancelled {
+        reason: String,
     },
+    // Join ticket was invalid, expired, for someone else, or already used. The socket is closed.
+    JoinRejected {
+        reason: String,
+    },
+    // Request couldn't be handled. The socket stays open unless the code is internal_error. Codes:
+    //   malformed_request       request wasn't valid JSON, or not a known request
+    //   non_text_message        request was sent as a binary message
+    //   not_in_game             player isn't in a game on this server
+    //   game_not_started        Move sent before both players joined
+    //   move_already_submitted  Move sent twice in the same round
+    //   game_over               request sent after the game ended
+    //   internal_error          server failed to handle the request, and closes the socket
+    Error {
+        code: String,
+        message: String,
+    },
+}
+
+impl ClientResponse {
+    pub fn error(code: &str, message: impl Into<String>) -> Self {
+        ClientResponse::Error {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ClientError> for ClientResponse {
+    fn from(error: ClientError) -> Self {
+        ClientResponse::error(error.code, error.message)
+    }
 }
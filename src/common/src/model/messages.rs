@@ -45,16 +45,39 @@ impl Serialize for Id {
 }
 
 // Websocket messages
+// First message on every socket, identifying the user with a session token from matchmaking
 #[derive(Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "camelCase")]
 pub struct OpenSocketRequest {
+    pub token: String,
+}
+
+// Client asking matchmaking for a session token. Sending a still-valid token renews it for the
+// same user, otherwise a new user is created.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SessionRequest {
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SessionResponse {
     pub user_id: Id,
+    pub token: String,
+}
+
+// Body of an error returned by a REST API
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorResponse {
+    // Stable identifier for the kind of error, e.g. "duplicate_players"
+    pub code: String,
+    pub message: String,
 }
 
 // Matchmaking <-> Game server interface
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct CreateGameRequest {
-    pub players: Vec<Id>, // TODO: into tuple
+    // Id matchmaking gave the match. Repeating a request for the same id returns the same game.
+    pub game_id: Id,
+    pub players: (Id, Id),
     pub games_to_win: u8,
 }
 
@@ -64,6 +87,41 @@ pub struct CreateGameResponse {
     pub address: String,
 }
 
+// Current load of a game server, used to place new games
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GameServerStatsResponse {
+    pub live_games: u32,
+}
+
+// Game server asking matchmaking to place games on it, sent on startup
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RegisterGameServerRequest {
+    // Base URL of the game server's REST API
+    pub url: String,
+    // Address players connect to
+    pub socket_address: String,
+    // Most games the server will host at once. None is unbounded.
+    pub capacity: Option<u32>,
+    pub version: String,
+}
+
+// Sent periodically by registered game servers. Servers that stop sending these are evicted.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GameServerHeartbeatRequest {
+    pub url: String,
+    pub live_games: u32,
+}
+
+// A game server as matchmaking knows it. Servers from config that never registered only have a url.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GameServerInfo {
+    pub url: String,
+    pub socket_address: Option<String>,
+    pub capacity: Option<u32>,
+    pub version: Option<String>,
+    pub live_games: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetGameRequest {
     pub game_id: Id,
@@ -82,3 +140,11 @@ pub struct PostGameResultsRequest {
     pub games_to_win: u8,
     pub games_won: (u8, u8),
 }
+
+// Game was cancelled because not every player joined in time
+#[derive(Serialize, Deserialize)]
+pub struct PostGameAbandonedRequest {
+    pub game_id: Id,
+    pub players: (Id, Id),
+    pub joined: Vec<Id>,
+}
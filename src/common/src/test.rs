@@ -7,16 +7,47 @@ use futures_util::{
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::{net::TcpStream, time::timeout};
-use tokio_tungstenite::{
-    connect_async,
-    tungstenite::{http::response, Message},
-    MaybeTlsStream, WebSocketStream,
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::timeout,
 };
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 use tracing::{debug, info};
-use uuid::Uuid;
 
-use crate::model::messages::{Id, OpenSocketRequest};
+use crate::{
+    auth::{self, SESSION_TOKEN_TTL},
+    model::messages::{Id, OpenSocketRequest},
+};
+
+// Secret servers under test are configured with, so test cases can mint session tokens
+pub const TEST_TOKEN_SECRET: &str = "test-token-secret";
+
+// Listener on a free local port, and its address. Servers under test are handed the bound
+// listener, so no other test can take the port before they start.
+pub async fn local_listener() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind local listener");
+    let address = listener
+        .local_addr()
+        .expect("Failed to get listener address")
+        .to_string();
+    (listener, address)
+}
+
+// Bad tokens for a user, bound to ${<name>_forged_token} and ${<name>_expired_token}
+pub fn bad_token_replacements(name: &str, user_id: Id) -> Vec<(String, String)> {
+    vec![
+        (
+            format!("{}_forged_token", name),
+            auth::issue_token(b"not-the-test-secret", user_id, SESSION_TOKEN_TTL),
+        ),
+        (
+            format!("{}_expired_token", name),
+            auth::issue_token(TEST_TOKEN_SECRET.as_bytes(), user_id, Duration::ZERO),
+        ),
+    ]
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -24,18 +55,41 @@ enum Event<RQ, RS, RestRq, RestRs>
 where
     RS: Serialize,
 {
+    // Identify as user_id with a freshly minted token, or send `token` as-is when given
     SocketOpen {
         name: String,
         user_id: Id,
+        token: Option<String>,
     },
     SocketSend {
         name: String,
         request: RQ,
     },
+    // Send a text frame as-is, for messages a well-behaved client wouldn't send
+    SocketSendRaw {
+        name: String,
+        body: String,
+    },
+    SocketClose {
+        name: String,
+    },
+    // Expect the server to close the socket with the given close code
+    SocketExpectClose {
+        name: String,
+        code: u16,
+    },
+    // Capture maps response fields to variables, which later requests can use as ${variable}.
+    // Captured fields aren't compared.
     SocketReceive {
         name: String,
         response: RS,
         replace_uuids: Option<bool>,
+        capture: Option<HashMap<String, String>>,
+    },
+    // Receive a message outside the socket's protocol. Only the fields given are compared.
+    SocketReceiveRaw {
+        name: String,
+        response: serde_json::Value,
     },
     Post {
         name: String,
@@ -48,6 +102,9 @@ where
     Comment {
         text: String,
     },
+    Sleep {
+        millis: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -81,7 +138,7 @@ fn substitute_ids(text: &str, id: Id) -> String {
         regex::Regex::new(r"[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}")
             .expect("Failed to compile UUID regex");
 
-    return uuid_regex.replace_all(text, id.to_string()).to_string();
+    uuid_regex.replace_all(text, id.to_string()).to_string()
 }
 
 // TODO: how to pass N generics?
@@ -122,8 +179,13 @@ where
         test_case
     }
 
-    pub async fn run(&self, address_lookup: HashMap<String, ServerAddress>) {
+    // Run the test case, returning the variables it captured
+    pub async fn run(
+        &self,
+        address_lookup: HashMap<String, ServerAddress>,
+    ) -> HashMap<String, String> {
         let timeout_len = Duration::from_millis(250);
+        let mut captured: HashMap<String, String> = HashMap::new();
         let mut server_handles = HashMap::new();
         for (id, address) in address_lookup {
             server_handles.insert(
@@ -144,20 +206,64 @@ where
         // TODO: remove duplicate code (fr fr)
         for event in self.sequence.iter() {
             match event {
-                Event::SocketOpen { name, user_id } => {
-                    let body: String = json!(OpenSocketRequest { user_id: *user_id }).to_string();
+                Event::SocketOpen {
+                    name,
+                    user_id,
+                    token,
+                } => {
+                    let token = token.clone().unwrap_or_else(|| {
+                        auth::issue_token(TEST_TOKEN_SECRET.as_bytes(), *user_id, SESSION_TOKEN_TTL)
+                    });
+                    let body: String = json!(OpenSocketRequest { token }).to_string();
                     let handle = server_handles.get_mut(name).expect("Send socket not found");
                     Self::socket_send(timeout_len, handle, body).await;
                 }
                 Event::SocketSend { name, request } => {
-                    let body: String = json!(request).to_string();
+                    let mut body: String = json!(request).to_string();
+                    for (variable, value) in captured.iter() {
+                        body = body.replace(&format!("${{{}}}", variable), value);
+                    }
+                    let handle = server_handles.get_mut(name).expect("Send socket not found");
+                    Self::socket_send(timeout_len, handle, body).await;
+                }
+                Event::SocketSendRaw { name, body } => {
                     let handle = server_handles.get_mut(name).expect("Send socket not found");
                     Self::socket_send(timeout_len, handle, body).await;
                 }
+                Event::SocketClose { name } => {
+                    let handle = server_handles
+                        .get_mut(name)
+                        .expect("Close socket not found");
+                    let ServerHandle::WebSocket { ref mut write, .. } = handle else {
+                        panic!("Expected WebSocket handle at {:}", name);
+                    };
+                    timeout(timeout_len, write.send(Message::Close(None)))
+                        .await
+                        .expect("Timeout closing socket")
+                        .expect("Failed to close socket");
+                }
+                Event::SocketExpectClose { name, code } => {
+                    let handle = server_handles.get_mut(name).expect("Socket not found");
+                    let ServerHandle::WebSocket { ref mut read, .. } = handle else {
+                        panic!("Expected WebSocket handle at {:}", name);
+                    };
+                    let message = timeout(timeout_len, read.next())
+                        .await
+                        .unwrap_or_else(|e| {
+                            panic!("Timeout (error {:?}) waiting for close code {}", e, code)
+                        })
+                        .expect("No message found")
+                        .expect("Failed to read message");
+                    let Message::Close(Some(frame)) = message else {
+                        panic!("Expected close frame, got {:?}", message);
+                    };
+                    assert_eq!(*code, u16::from(frame.code));
+                }
                 Event::SocketReceive {
                     name,
                     response: expected,
                     replace_uuids,
+                    capture,
                 } => {
                     let handle = server_handles.get_mut(name).expect("Send socket not found");
                     let ServerHandle::WebSocket {
@@ -174,11 +280,16 @@ where
                         })
                         .expect("No message found")
                         .expect("Failed to read message");
-                    let response_text = body
+                    let mut response_text = body
                         .to_text()
                         .expect("Failed to convert response to text")
                         .to_string();
 
+                    if let Some(capture) = capture {
+                        response_text =
+                            Self::capture_fields(&response_text, expected, capture, &mut captured);
+                    }
+
                     // If replace_uuids is true, replace all UUIDs with a fixed value
                     if replace_uuids.unwrap_or(false) {
                         Self::compare_with_uuid_replacement::<RS>(&response_text, expected);
@@ -188,6 +299,30 @@ where
                         assert_eq!(expected, &response);
                     }
                 }
+                Event::SocketReceiveRaw {
+                    name,
+                    response: expected,
+                } => {
+                    let handle = server_handles.get_mut(name).expect("Socket not found");
+                    let ServerHandle::WebSocket { ref mut read, .. } = handle else {
+                        panic!("Expected WebSocket handle at {:}", name);
+                    };
+                    let body = timeout(timeout_len, read.next())
+                        .await
+                        .unwrap_or_else(|e| {
+                            panic!("Timeout (error {:?}) waiting for {:?}", e, expected)
+                        })
+                        .expect("No message found")
+                        .expect("Failed to read message");
+                    let response: serde_json::Value = serde_json::from_str(
+                        body.to_text().expect("Failed to convert response to text"),
+                    )
+                    .expect("Failed to deserialize response");
+                    let expected = expected.as_object().expect("Expected a JSON object");
+                    for (field, value) in expected {
+                        assert_eq!(Some(value), response.get(field), "Field {}", field);
+                    }
+                }
                 Event::Post {
                     name,
                     endpoint,
@@ -230,8 +365,33 @@ where
                 Event::Comment { text } => {
                     info!("Comment: {:}", text)
                 }
+                Event::Sleep { millis } => {
+                    tokio::time::sleep(Duration::from_millis(*millis)).await;
+                }
             }
         }
+        captured
+    }
+
+    // Store the captured fields of a response, and overwrite them with the expected values
+    fn capture_fields(
+        response_text: &str,
+        expected: &RS,
+        capture: &HashMap<String, String>,
+        captured: &mut HashMap<String, String>,
+    ) -> String {
+        let mut response: serde_json::Value =
+            serde_json::from_str(response_text).expect("Failed to deserialize response");
+        let expected = json!(expected);
+        for (field, variable) in capture {
+            let value = response
+                .get(field)
+                .and_then(|value| value.as_str())
+                .unwrap_or_else(|| panic!("No string field {} to capture", field));
+            captured.insert(variable.clone(), value.to_string());
+            response[field] = expected[field].clone();
+        }
+        response.to_string()
     }
 
     async fn socket_send(timeout_len: Duration, handle: &mut ServerHandle, body: impl ToString) {
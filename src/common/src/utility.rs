@@ -1,7 +1,6 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc, time::Duration};
 
 use tokio::{
-    net::UdpSocket,
     signal::{self},
     sync::{
         broadcast,
@@ -55,15 +54,40 @@ pub async fn create_shutdown_channel() -> broadcast::Receiver<()> {
     shutdown_receiver
 }
 
-pub async fn random_address() -> String {
-    let socket = UdpSocket::bind("0.0.0.0:0")
-        .await
-        .expect("Failed to get random port");
-    socket
-        .local_addr()
-        .expect("Failed to unwrap local address")
-        .to_string()
+// How many times to try an operation, and how long to wait between tries
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    // Attempts before giving up, counting the first
+    pub attempts: u32,
+    // Delay before the first retry, doubled after every failed attempt
+    pub initial_delay: Duration,
 }
+
+// Run an operation until it succeeds, fails with an error should_retry rejects, or runs out of
+// attempts, and return its last result. The operation is given the attempt number, from 1.
+pub async fn retry<T, E, F, Fut>(
+    backoff: Backoff,
+    mut operation: F,
+    should_retry: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = backoff.initial_delay;
+    let mut attempt = 1;
+    loop {
+        match operation(attempt).await {
+            Err(e) if attempt < backoff.attempts && should_retry(&e) => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub fn url<A, B, C>(protocol: A, base_url: B, endpoint: C) -> String
 where
     A: ToString,
@@ -1,7 +1,10 @@
-use std::net::SocketAddr;
+use std::{fmt, net::SocketAddr, time::Duration};
 
 use async_trait::async_trait;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
 use serde::{Deserialize, Serialize};
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -13,17 +16,104 @@ use tokio::{
 use tokio_tungstenite::{
     accept_async,
     tungstenite::{
+        self,
         protocol::{frame::coding::CloseCode, CloseFrame},
         Message,
     },
+    WebSocketStream,
 };
 use tracing::{debug, error, info, warn};
 
 use crate::{
+    auth::{self, INVALID_TOKEN_CLOSE_CODE},
     model::messages::{Id, OpenSocketRequest},
     utility::Channel,
 };
 
+// Time a client has to identify itself after connecting, unless configured otherwise
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Unparseable identification messages tolerated before the socket is closed
+const MAX_HANDSHAKE_ATTEMPTS: u32 = 3;
+// Close codes for failed handshakes, next to auth's INVALID_TOKEN_CLOSE_CODE
+pub const HANDSHAKE_TIMEOUT_CLOSE_CODE: u16 = 4002;
+pub const MALFORMED_HANDSHAKE_CLOSE_CODE: u16 = 4003;
+
+type SocketWriteHandle = SplitSink<WebSocketStream<TcpStream>, Message>;
+type SocketReadHandle = SplitStream<WebSocketStream<TcpStream>>;
+
+// Settings shared by every connection a listener accepts
+#[derive(Clone)]
+pub struct SocketConfig {
+    // Secret session tokens are verified with
+    pub token_secret: String,
+    // Time a client has to send a valid OpenSocketRequest before being disconnected
+    pub handshake_timeout: Duration,
+}
+
+// Error reported to a client by the websocket layer, which every protocol sends as its own
+// Error { code, message } response
+#[derive(Debug)]
+pub struct ClientError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum WebsocketError {
+    // Client sent text that isn't a valid request
+    Parse(serde_json::Error),
+    // Client sent a binary message
+    NonText,
+    // Response couldn't be serialized
+    Serialize(serde_json::Error),
+    // Connection to the client failed
+    Transport(Box<tungstenite::Error>),
+    // Server couldn't pass the request on, so it can't serve this client any more
+    Internal(String),
+}
+
+impl WebsocketError {
+    // Whether the session can carry on after telling the client about the error
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, WebsocketError::Parse(_) | WebsocketError::NonText)
+    }
+
+    // Error telling the client what was wrong with what they sent
+    pub fn to_client_error(&self) -> ClientError {
+        let code = match self {
+            WebsocketError::Parse(_) => "malformed_request",
+            WebsocketError::NonText => "non_text_message",
+            WebsocketError::Serialize(_)
+            | WebsocketError::Transport(_)
+            | WebsocketError::Internal(_) => "internal_error",
+        };
+        ClientError {
+            code,
+            message: self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for WebsocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebsocketError::Parse(e) => write!(f, "Could not parse request: {}", e),
+            WebsocketError::NonText => write!(f, "Expected a text message"),
+            WebsocketError::Serialize(e) => write!(f, "Could not serialize response: {}", e),
+            WebsocketError::Transport(e) => write!(f, "Connection failed: {}", e),
+            WebsocketError::Internal(reason) => write!(f, "Internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for WebsocketError {}
+
+impl From<tungstenite::Error> for WebsocketError {
+    fn from(error: tungstenite::Error) -> Self {
+        WebsocketError::Transport(Box::new(error))
+    }
+}
+
 #[derive(Clone)]
 pub struct Connection<RS>
 where
@@ -46,20 +136,19 @@ pub trait WebsocketHandler<ExternalRQ, ExternalRS, InternalRQ>
 where
     Self: 'static,
     ExternalRQ: for<'de> Deserialize<'de> + Send + 'static,
-    ExternalRS: Clone + Send + Serialize + 'static,
+    ExternalRS: Clone + Send + Serialize + From<ClientError> + 'static,
     InternalRQ: Clone + Send + 'static,
 {
     async fn listen(
         &mut self,
-        address: String,
+        ws_listener: TcpListener,
+        config: SocketConfig,
         shutdown_receiver: &mut broadcast::Receiver<()>,
         mm_sender: Sender<InternalRQ>,
     ) {
-        let ws_listener = TcpListener::bind(address.clone())
-            .await
-            .unwrap_or_else(|e| {
-                panic!("Failed to bind to {}: {}", address, e);
-            });
+        let address = ws_listener
+            .local_addr()
+            .expect("Failed to get listener address");
         info!("Initialized ws listener: {}", address);
         loop {
             tokio::select! {
@@ -72,6 +161,8 @@ where
                             tokio::spawn(Self::connection_thread(
                                 stream,
                                 address,
+                                config.clone(),
+                                shutdown_receiver.resubscribe(),
                                 mm_sender.clone(),
                             ));
                         }
@@ -89,99 +180,118 @@ where
     async fn connection_thread(
         stream: TcpStream,
         address: SocketAddr,
+        config: SocketConfig,
+        mut shutdown_receiver: broadcast::Receiver<()>,
         mm_sender: Sender<InternalRQ>,
     ) {
         info!("New ws connection: {}", address);
 
-        let stream = accept_async(stream).await.unwrap();
-        let (mut ws_sender, mut ws_receiver) = stream.split();
-        // The first message sent is always the user's id:
-        // TODO: extract to fn, add shutdown listener
-        let mut user_id = None;
-        while user_id.is_none() {
-            match ws_receiver.next().await {
-                None => {
-                    warn!("Connection closed before receiving user ID");
-                    return;
-                }
-                Some(Err(e)) => {
-                    warn!("Error receiving message: {}", e);
-                    continue;
-                }
-                Some(Ok(msg)) => {
-                    if let Ok(msg_str) = msg.to_text() {
-                        match serde_json::from_str::<OpenSocketRequest>(msg_str) {
-                            Ok(request) => {
-                                user_id = Some(request.user_id);
-                            }
-                            Err(error) => {
-                                warn!(
-                                    "Failed to parse identification message: {:?} ({:})",
-                                    error, msg
-                                );
-                            }
-                        }
-                    } else {
-                        warn!("Received non-text message");
-                    }
-                }
+        let stream = match accept_async(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Websocket handshake with {} failed: {}", address, e);
+                return;
             }
-        }
-
-        let user_id = user_id.expect("UserID can't be null at this point, right..?");
+        };
+        let (mut ws_sender, mut ws_receiver) = stream.split();
+        let Some(user_id) = handshake(
+            &mut ws_sender,
+            &mut ws_receiver,
+            address,
+            &config,
+            &mut shutdown_receiver,
+        )
+        .await
+        else {
+            return;
+        };
 
         // Lookup user's Connection by user_id
         let (to_user_sender, mut to_user_receiver) = mpsc::channel::<ExternalRS>(100);
 
         debug!("Listening to {:?}", user_id);
         loop {
-            let mut close_socket = false;
-            tokio::select! {
+            // Response to send, if any, or what went wrong
+            let result = tokio::select! {
+                _ = shutdown_receiver.recv() => {
+                    close(&mut ws_sender, CloseCode::Away, "Server shutting down").await;
+                    break;
+                }
+
                 // Poll connection for any push messages
                 msg = to_user_receiver.recv() => {
                     let Some(msg) = msg else { continue };
-                    let Some(response) = Self::handle_internal_message(msg).await else { return };
-                    let response_body = serde_json::to_string(&response).expect("Could not serialize response.");
-                    ws_sender.send(Message::Text(response_body)).await.unwrap();
-
-                    // Drop connection according to criteria
-                    close_socket |= Self::drop_after_send(response);
+                    let Some(response) = Self::handle_internal_message(msg).await else { break };
+                    Ok(Some(response))
                 }
 
                 // Otherwise, handle incoming messages
                 msg = ws_receiver.next() => {
                     debug!("msg: {:?}", msg);
-                    let Some(msg) = msg else {
-                        debug!("WS received empty message, TODO"); // TODO: what to do
-                        break;
-                    };
-                    let msg = msg.expect("Couldn't unwrap message");
-
-                    let result = Self::handle_external_message(
-                        user_id,
-                        msg,
-                        to_user_sender.clone(),
-                        mm_sender.clone()
-                    ).await;
-
-                    let Ok(Some(response)) = result else { continue };
-                    let response_body = serde_json::to_string(&response).expect("Could not serialize response.");
-                    ws_sender.send(Message::Text(response_body)).await.unwrap();
+                    match msg {
+                        None | Some(Ok(Message::Close(_))) => {
+                            debug!("Connection closed by {:?}", user_id);
+                            break;
+                        }
+                        Some(Err(e)) => Err(e.into()),
+                        Some(Ok(msg)) => Self::handle_external_message(
+                            user_id,
+                            msg,
+                            to_user_sender.clone(),
+                            mm_sender.clone()
+                        ).await,
+                    }
+                }
+            };
 
+            // Serialize before sending, so the response isn't held across the send
+            let outgoing = match result {
+                Ok(None) => Ok(None),
+                Ok(Some(response)) => to_message(&response)
+                    .map(|message| Some((message, Self::drop_after_send(response)))),
+                // The client sent something we couldn't handle, tell them and carry on
+                Err(error) if error.is_recoverable() => {
+                    warn!("Bad message from {:?}: {}", user_id, error);
+                    to_message(&ExternalRS::from(error.to_client_error()))
+                        .map(|message| Some((message, false)))
+                }
+                // The server failed, tell the client why before hanging up
+                Err(error @ WebsocketError::Internal(_)) => {
+                    error!("Failed to handle request from {:?}: {}", user_id, error);
+                    if let Ok(message) = to_message(&ExternalRS::from(error.to_client_error())) {
+                        if let Err(e) = ws_sender.send(message).await {
+                            debug!("Failed to send internal error: {}", e);
+                        }
+                    }
+                    close(&mut ws_sender, CloseCode::Error, "Internal error").await;
+                    break;
+                }
+                Err(error) => Err(error),
+            };
+            let sent = match outgoing {
+                Ok(None) => Ok(()),
+                Ok(Some((message, close_after))) => {
+                    let sent = ws_sender.send(message).await.map_err(WebsocketError::from);
                     // Drop connection according to criteria
-                    close_socket |= Self::drop_after_send(response);
+                    if sent.is_ok() && close_after {
+                        close(
+                            &mut ws_sender,
+                            CloseCode::Normal,
+                            "Decided to close after sending the previous message",
+                        )
+                        .await;
+                    }
+                    sent
                 }
+                Err(error) => Err(error),
             };
-            if close_socket {
-                ws_sender
-                    .send(Message::Close(Some(CloseFrame {
-                        code: CloseCode::Normal,
-                        reason: "Decided to close after sending the previous message".into(),
-                    })))
-                    .await
-                    .expect("Failed to close socket");
+            if let Err(error) = sent {
+                warn!("Ending session for {:?}: {}", user_id, error);
+                break;
             }
         }
+
+        Self::on_disconnect(user_id, mm_sender).await;
     }
     // Read message from connection, return immediate response
     // TODO: do we need the sender here if we're not responding immediately?
@@ -190,19 +300,18 @@ where
         message: Message,
         to_user: Sender<ExternalRS>,
         to_internal: Sender<InternalRQ>,
-    ) -> Result<Option<ExternalRS>, &'static str> {
-        if !message.is_text() {
-            return Err("Got non-text message :(");
-        }
+    ) -> Result<Option<ExternalRS>, WebsocketError> {
+        let body = match message {
+            Message::Text(body) => body,
+            // Keepalives are answered by tungstenite
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => return Ok(None),
+            _ => return Err(WebsocketError::NonText),
+        };
 
         // Deserialize request
-        let body = message.to_text().unwrap();
-        let request: ExternalRQ =
-            serde_json::from_str(body).expect("Could not deserialize request.");
-
-        let response = Self::respond_to_request(user_id, request, to_user, to_internal).await;
+        let request: ExternalRQ = serde_json::from_str(&body).map_err(WebsocketError::Parse)?;
 
-        Ok(response)
+        Self::respond_to_request(user_id, request, to_user, to_internal).await
     }
 
     // Read internal message to potentially forward to the user.
@@ -211,6 +320,9 @@ where
         Some(body)
     }
 
+    // Clean up after a user once their connection is gone. By default, do nothing.
+    async fn on_disconnect(_user_id: Id, _to_internal: Sender<InternalRQ>) {}
+
     // Criterion to drop connection. By default, always keep the connection alive.
     fn drop_after_send(_response: ExternalRS) -> bool {
         false
@@ -222,7 +334,94 @@ where
         _request: ExternalRQ,
         _to_user: Sender<ExternalRS>,
         _to_internal: Sender<InternalRQ>,
-    ) -> Option<ExternalRS> {
-        None
+    ) -> Result<Option<ExternalRS>, WebsocketError> {
+        Ok(None)
+    }
+}
+
+// Wait for the client to identify itself with a session token. Returns None, having closed the
+// socket where possible, if the client doesn't do so in time or the server shuts down first.
+async fn handshake(
+    ws_sender: &mut SocketWriteHandle,
+    ws_receiver: &mut SocketReadHandle,
+    address: SocketAddr,
+    config: &SocketConfig,
+    shutdown_receiver: &mut broadcast::Receiver<()>,
+) -> Option<Id> {
+    let deadline = tokio::time::sleep(config.handshake_timeout);
+    tokio::pin!(deadline);
+    let mut malformed = 0;
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => msg,
+            _ = &mut deadline => {
+                warn!("{} didn't identify itself in time", address);
+                let code = CloseCode::Library(HANDSHAKE_TIMEOUT_CLOSE_CODE);
+                close(ws_sender, code, "Handshake timed out").await;
+                return None;
+            }
+            _ = shutdown_receiver.recv() => {
+                close(ws_sender, CloseCode::Away, "Server shutting down").await;
+                return None;
+            }
+        };
+        let msg = match msg {
+            None | Some(Ok(Message::Close(_))) => {
+                warn!("Connection closed before receiving session token");
+                return None;
+            }
+            Some(Err(e)) => {
+                warn!("Error receiving session token from {}: {}", address, e);
+                return None;
+            }
+            // Keepalives don't count against the client
+            Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+            Some(Ok(msg)) => msg,
+        };
+
+        let request = msg.to_text().map_err(|e| e.to_string()).and_then(|text| {
+            serde_json::from_str::<OpenSocketRequest>(text).map_err(|e| e.to_string())
+        });
+        match request {
+            Ok(request) => match auth::verify_token(config.token_secret.as_bytes(), &request.token)
+            {
+                Ok(user_id) => return Some(user_id),
+                Err(error) => {
+                    warn!("Rejected session token from {}: {}", address, error);
+                    let code = CloseCode::Library(INVALID_TOKEN_CLOSE_CODE);
+                    close(ws_sender, code, &error.to_string()).await;
+                    return None;
+                }
+            },
+            Err(error) => {
+                malformed += 1;
+                warn!(
+                    "Failed to parse identification message from {} ({}/{}): {}",
+                    address, malformed, MAX_HANDSHAKE_ATTEMPTS, error
+                );
+                if malformed >= MAX_HANDSHAKE_ATTEMPTS {
+                    let code = CloseCode::Library(MALFORMED_HANDSHAKE_CLOSE_CODE);
+                    close(ws_sender, code, "Too many malformed handshake messages").await;
+                    return None;
+                }
+            }
+        }
     }
 }
+
+// Send a close frame. The client may already be gone, in which case there's nobody to tell.
+async fn close(ws_sender: &mut SocketWriteHandle, code: CloseCode, reason: &str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    if let Err(e) = ws_sender.send(Message::Close(Some(frame))).await {
+        debug!("Failed to send close frame: {}", e);
+    }
+}
+
+fn to_message(body: &impl Serialize) -> Result<Message, WebsocketError> {
+    serde_json::to_string(body)
+        .map(Message::Text)
+        .map_err(WebsocketError::Serialize)
+}
@@ -1,20 +1,53 @@
 use super::model::internal::GameRequest;
 use super::service::game_manager::GameManager;
 use super::service::game_socket::GameSocket;
-use common::websocket::WebsocketHandler;
+use common::websocket::{SocketConfig, WebsocketHandler};
+use std::{io, time::Duration};
+use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
-use tracing::{info, Level};
+use tracing::Level;
 
 #[derive(Clone)]
 pub struct GameServerConfig {
     pub manager_address: String,
     pub socket_address: String,
+    // Base URL of the matchmaking REST API, used to register and report finished games
+    pub matchmaking_url: Option<String>,
+    // How often to send matchmaking our load once registered
+    pub heartbeat_interval: Duration,
+    // Most games hosted at once. Past this, create_game is refused with a 503. None is unbounded.
+    pub max_concurrent_games: Option<u32>,
+    // Time players have to submit each move. None waits forever.
+    pub move_timeout: Option<Duration>,
+    // Time players have to join a game after it's created. None waits forever.
+    pub join_timeout: Option<Duration>,
+    // Secret session tokens are verified with. Must match matchmaking's.
+    pub token_secret: String,
+    // Time clients have to identify themselves after opening a socket
+    pub handshake_timeout: Duration,
+}
+
+// Listeners for the REST api and the websocket, bound before the server starts
+pub struct GameServerListeners {
+    pub manager: TcpListener,
+    pub socket: TcpListener,
+}
+
+impl GameServerListeners {
+    // Bind the addresses in the config
+    pub async fn bind(config: &GameServerConfig) -> io::Result<Self> {
+        Ok(GameServerListeners {
+            manager: TcpListener::bind(&config.manager_address).await?,
+            socket: TcpListener::bind(&config.socket_address).await?,
+        })
+    }
 }
 
 pub async fn serve(
     config: GameServerConfig,
+    listeners: GameServerListeners,
     shutdown_receiver: tokio::sync::broadcast::Receiver<()>,
     ready_signal: Option<tokio::sync::oneshot::Sender<()>>,
 ) {
@@ -32,6 +65,7 @@ pub async fn serve(
         GameManager::new()
             .run(
                 manager_config,
+                listeners.manager,
                 &mut manager_shutdown_receiver,
                 to_game_receiver,
             )
@@ -41,7 +75,11 @@ pub async fn serve(
     let websocket_handle: JoinHandle<()> = tokio::spawn(async move {
         GameSocket::new()
             .listen(
-                config.socket_address,
+                listeners.socket,
+                SocketConfig {
+                    token_secret: config.token_secret,
+                    handshake_timeout: config.handshake_timeout,
+                },
                 &mut game_shutdown_receiver,
                 to_game_sender,
             )
@@ -66,7 +104,7 @@ pub struct GameServer {
     shutdown_sender: broadcast::Sender<()>,
 }
 impl GameServer {
-    pub async fn new(config: GameServerConfig) -> Self {
+    pub async fn new(config: GameServerConfig, listeners: GameServerListeners) -> Self {
         // Init logging, ignore error if already set
         let _ = tracing_subscriber::fmt()
             .with_line_number(true)
@@ -78,7 +116,12 @@ impl GameServer {
         let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
         let (ready_sender, ready_receiver) = tokio::sync::oneshot::channel();
 
-        tokio::spawn(serve(config.clone(), shutdown_receiver, Some(ready_sender)));
+        tokio::spawn(serve(
+            config.clone(),
+            listeners,
+            shutdown_receiver,
+            Some(ready_sender),
+        ));
 
         // Wait for server to be ready
         ready_receiver.await.expect("Server failed to start");
@@ -1,6 +1,8 @@
-use common::utility::create_shutdown_channel;
-use game_server::entrypoint::{self, GameServerConfig};
-use tracing::Level;
+use std::time::Duration;
+
+use common::{auth, utility::create_shutdown_channel, websocket::DEFAULT_HANDSHAKE_TIMEOUT};
+use game_server::entrypoint::{self, GameServerConfig, GameServerListeners};
+use tracing::{error, Level};
 
 #[tokio::main]
 async fn main() {
@@ -9,12 +11,26 @@ async fn main() {
         .with_file(true)
         .with_max_level(Level::DEBUG)
         .init();
+    let token_secret = auth::token_secret_from_env().unwrap_or_else(|e| {
+        error!("Can't start the game server: {}", e);
+        std::process::exit(1);
+    });
     let shutdown_receiver = create_shutdown_channel().await;
     let config = GameServerConfig {
         manager_address: "0.0.0.0:8082".to_owned(),
         socket_address: "0.0.0.0:3002".to_owned(),
+        matchmaking_url: Some("http://0.0.0.0:8081".to_owned()),
+        heartbeat_interval: Duration::from_secs(5),
+        max_concurrent_games: Some(1000),
+        move_timeout: Some(Duration::from_secs(30)),
+        join_timeout: Some(Duration::from_secs(30)),
+        token_secret,
+        handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
     };
-    entrypoint::serve(config, shutdown_receiver, None).await;
+    let listeners = GameServerListeners::bind(&config)
+        .await
+        .expect("Failed to bind game server addresses");
+    entrypoint::serve(config, listeners, shutdown_receiver, None).await;
 }
 
 /// TODO: Does this belong in tests/?
@@ -24,30 +40,52 @@ async fn main() {
 mod tests {
     use std::collections::HashMap;
 
+    use common::auth::{JOIN_TICKET_TTL, SESSION_TOKEN_TTL};
     use common::message::game_server::{ClientRequest, ClientResponse};
     use common::reqwest::{Client, StatusCode};
-    use common::utility::{random_address, url};
+    use common::utility::url;
     use common::{
         model::messages::{
-            CreateGameRequest, CreateGameResponse, GetGameRequest, GetGameResponse, Id,
+            CreateGameRequest, CreateGameResponse, ErrorResponse, GameServerStatsResponse,
+            GetGameRequest, GetGameResponse, Id, OpenSocketRequest,
+        },
+        test::{
+            bad_token_replacements, local_listener, ServerAddress, TestCase, TEST_TOKEN_SECRET,
         },
-        test::{ServerAddress, TestCase},
     };
     use entrypoint::GameServer;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{protocol::frame::coding::CloseCode, Message},
+    };
     use tracing::debug;
 
     use super::*;
-    async fn make_config() -> GameServerConfig {
-        GameServerConfig {
-            manager_address: random_address().await.to_string(),
-            socket_address: random_address().await.to_string(),
-        }
+    // Config for a server on local ports, with the listeners already bound to them
+    async fn make_config() -> (GameServerConfig, GameServerListeners) {
+        let (manager, manager_address) = local_listener().await;
+        let (socket, socket_address) = local_listener().await;
+        let config = GameServerConfig {
+            manager_address,
+            socket_address,
+            matchmaking_url: None,
+            heartbeat_interval: Duration::from_secs(5),
+            max_concurrent_games: None,
+            move_timeout: None,
+            join_timeout: None,
+            token_secret: TEST_TOKEN_SECRET.to_owned(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        };
+        (config, GameServerListeners { manager, socket })
     }
 
     #[tokio::test]
     async fn serves_hello_world() {
         // Given
-        let server = GameServer::new(make_config().await).await;
+        let (config, listeners) = make_config().await;
+        let server = GameServer::new(config, listeners).await;
 
         // When
         let response = Client::new()
@@ -65,11 +103,13 @@ mod tests {
     }
     #[tokio::test]
     async fn can_create_game() {
-        let server = GameServer::new(make_config().await).await;
+        let (config, listeners) = make_config().await;
+        let server = GameServer::new(config, listeners).await;
 
         // POST game
         let request = CreateGameRequest {
-            players: vec![Id::new(), Id::new()],
+            game_id: Id::new(),
+            players: (Id::new(), Id::new()),
             games_to_win: 3,
         };
         let client = Client::new();
@@ -114,17 +154,186 @@ mod tests {
         server.shutdown().await;
     }
 
+    // POST a raw body to create_game, returning the status and error body
+    async fn create_game_rejection(body: String) -> (StatusCode, ErrorResponse) {
+        let (config, listeners) = make_config().await;
+        let server = GameServer::new(config, listeners).await;
+        let response = Client::new()
+            .post(url(
+                "http",
+                server.config.manager_address.clone(),
+                "create_game",
+            ))
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .expect("Request failed");
+        let status = response.status();
+        let error = response
+            .json::<ErrorResponse>()
+            .await
+            .expect("Failed to get error response body");
+        server.shutdown().await;
+        (status, error)
+    }
+
     #[tokio::test]
-    async fn run_game() {
-        let config = make_config().await;
-        let server = GameServer::new(config.clone()).await;
-        let file_path = env!("CARGO_MANIFEST_DIR").to_string() + "/test/data/full_game.json";
+    async fn rejects_malformed_json() {
+        let (status, error) = create_game_rejection("{\"players\": [".to_string()).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("invalid_body", error.code);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_number_of_players() {
+        let body = json!({
+            "game_id": Id::new(),
+            "players": [Id::new(), Id::new(), Id::new()],
+            "games_to_win": 1,
+        });
+        let (status, error) = create_game_rejection(body.to_string()).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("invalid_body", error.code);
+    }
+
+    #[tokio::test]
+    async fn rejects_zero_games_to_win() {
+        let body = json!({
+            "game_id": Id::new(),
+            "players": [Id::new(), Id::new()],
+            "games_to_win": 0,
+        });
+        let (status, error) = create_game_rejection(body.to_string()).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!("invalid_games_to_win", error.code);
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicate_players() {
+        let player = Id::new();
+        let body = json!({
+            "game_id": Id::new(),
+            "players": [player, player],
+            "games_to_win": 1,
+        });
+        let (status, error) = create_game_rejection(body.to_string()).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!("duplicate_players", error.code);
+    }
+
+    #[tokio::test]
+    async fn refuses_games_past_capacity() {
+        let (config, listeners) = make_config().await;
+        let config = GameServerConfig {
+            max_concurrent_games: Some(1),
+            ..config
+        };
+        let server = GameServer::new(config, listeners).await;
+        let client = Client::new();
+        let create_url = url("http", server.config.manager_address.clone(), "create_game");
+
+        let mut statuses = vec![];
+        for _ in 0..2 {
+            let response = client
+                .post(create_url.clone())
+                .json(&CreateGameRequest {
+                    game_id: Id::new(),
+                    players: (Id::new(), Id::new()),
+                    games_to_win: 1,
+                })
+                .send()
+                .await
+                .expect("Request failed");
+            statuses.push((
+                response.status(),
+                response.headers().get("retry-after").cloned(),
+            ));
+        }
+        assert_eq!(StatusCode::CREATED, statuses[0].0);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, statuses[1].0);
+        assert!(statuses[1].1.is_some());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn reports_live_games() {
+        let (config, listeners) = make_config().await;
+        let server = GameServer::new(config, listeners).await;
+        let client = Client::new();
+        let stats_url = url("http", server.config.manager_address.clone(), "stats");
+
+        // No games yet
+        let stats = client
+            .get(stats_url.clone())
+            .send()
+            .await
+            .expect("Request failed")
+            .json::<GameServerStatsResponse>()
+            .await
+            .expect("Failed to get stats response body");
+        assert_eq!(0, stats.live_games);
+
+        // One game after creating it
+        let request = CreateGameRequest {
+            game_id: Id::new(),
+            players: (Id::new(), Id::new()),
+            games_to_win: 1,
+        };
+        client
+            .post(url(
+                "http",
+                server.config.manager_address.clone(),
+                "create_game",
+            ))
+            .json(&request)
+            .send()
+            .await
+            .expect("Request failed");
+        let stats = client
+            .get(stats_url)
+            .send()
+            .await
+            .expect("Request failed")
+            .json::<GameServerStatsResponse>()
+            .await
+            .expect("Failed to get stats response body");
+        assert_eq!(1, stats.live_games);
+
+        server.shutdown().await;
+    }
+
+    async fn run_test_case(file_name: &str) {
+        run_test_case_with_config(file_name, |config| config).await;
+    }
+
+    // Run a scenario against a server whose config is adjusted by `configure`
+    async fn run_test_case_with_config(
+        file_name: &str,
+        configure: impl FnOnce(GameServerConfig) -> GameServerConfig,
+    ) {
+        let (config, listeners) = make_config().await;
+        let config = configure(config);
+        let server = GameServer::new(config.clone(), listeners).await;
+        let file_path = env!("CARGO_MANIFEST_DIR").to_string() + "/test/data/" + file_name;
         let ids = [Id::new(), Id::new()];
+        let game_id = Id::new();
         let replacements = vec![
             ("user1", ids[0].to_string()),
             ("user2", ids[1].to_string()),
             ("socket_address", config.socket_address.to_string()),
+            ("game_id", game_id.to_string()),
+            ("second_game_id", Id::new().to_string()),
+            ("third_game_id", Id::new().to_string()),
         ];
+        let replacements: Vec<(String, String)> = replacements
+            .into_iter()
+            .map(|(from, to)| (from.to_string(), to))
+            .chain(bad_token_replacements("user1", ids[0]))
+            .chain(bad_token_replacements("user2", ids[1]))
+            .chain(ticket_replacements(game_id, &ids))
+            .collect();
         let test_case =
             TestCase::<ClientRequest, ClientResponse, CreateGameRequest, CreateGameResponse>::load(
                 file_path,
@@ -140,11 +349,157 @@ mod tests {
                 "user2".to_string(),
                 ServerAddress::WebSocket(url("ws", server.config.socket_address.clone(), "")),
             ),
+            (
+                "user1_reconnect".to_string(),
+                ServerAddress::WebSocket(url("ws", server.config.socket_address.clone(), "")),
+            ),
+            (
+                "user2_reconnect".to_string(),
+                ServerAddress::WebSocket(url("ws", server.config.socket_address.clone(), "")),
+            ),
             (
                 "rest".to_string(),
                 ServerAddress::RestApi(url("http", server.config.manager_address.clone(), "")),
             ),
         ]);
         test_case.run(address_lookup).await;
+        server.shutdown().await;
+    }
+
+    // Join tickets for ${game_id}, bound to ${userN_ticket} and ${userN_reconnect_ticket}, plus an
+    // already expired ${user1_expired_ticket}
+    fn ticket_replacements(game_id: Id, ids: &[Id]) -> Vec<(String, String)> {
+        let secret = TEST_TOKEN_SECRET.as_bytes();
+        let mut replacements = vec![(
+            "user1_expired_ticket".to_string(),
+            auth::issue_join_ticket(secret, game_id, ids[0], Duration::ZERO),
+        )];
+        for (i, id) in ids.iter().enumerate() {
+            for name in [format!("user{}", i + 1), format!("user{}_reconnect", i + 1)] {
+                replacements.push((
+                    format!("{}_ticket", name),
+                    auth::issue_join_ticket(secret, game_id, *id, JOIN_TICKET_TTL),
+                ));
+            }
+        }
+        replacements
+    }
+
+    #[tokio::test]
+    async fn run_game() {
+        run_test_case("full_game.json").await;
+    }
+
+    #[tokio::test]
+    async fn players_can_rejoin_after_game() {
+        run_test_case("rematch.json").await;
+    }
+
+    #[tokio::test]
+    async fn drawn_rounds_are_replayed() {
+        run_test_case("tied_game.json").await;
+    }
+
+    #[tokio::test]
+    async fn missed_moves_time_out() {
+        run_test_case_with_config("move_timeout.json", |config| GameServerConfig {
+            move_timeout: Some(Duration::from_millis(100)),
+            ..config
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn players_who_left_time_out() {
+        run_test_case_with_config("move_timeout_after_close.json", |config| GameServerConfig {
+            move_timeout: Some(Duration::from_millis(100)),
+            ..config
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn game_is_cancelled_when_a_player_never_joins() {
+        run_test_case_with_config("join_timeout.json", |config| GameServerConfig {
+            join_timeout: Some(Duration::from_millis(100)),
+            ..config
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn players_can_reconnect_mid_game() {
+        run_test_case("reconnect.json").await;
+    }
+
+    #[tokio::test]
+    async fn players_can_reconnect_after_their_socket_closed() {
+        run_test_case("reconnect_after_close.json").await;
+    }
+
+    #[tokio::test]
+    async fn sockets_with_invalid_tokens_are_closed() {
+        run_test_case("invalid_token.json").await;
+    }
+
+    #[tokio::test]
+    async fn invalid_join_tickets_are_rejected() {
+        run_test_case("invalid_ticket.json").await;
+    }
+
+    #[tokio::test]
+    async fn handshake_times_out() {
+        run_test_case_with_config("handshake_timeout.json", |config| GameServerConfig {
+            handshake_timeout: Duration::from_millis(100),
+            ..config
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn malformed_handshakes_are_capped() {
+        run_test_case("malformed_handshake.json").await;
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_an_error() {
+        run_test_case("malformed_request.json").await;
+    }
+
+    #[tokio::test]
+    async fn out_of_phase_requests_get_an_error() {
+        run_test_case("out_of_phase.json").await;
+    }
+
+    #[tokio::test]
+    async fn sockets_are_closed_on_shutdown() {
+        let (config, listeners) = make_config().await;
+        let server = GameServer::new(config, listeners).await;
+        let (mut socket, _) = connect_async(url("ws", server.config.socket_address.clone(), ""))
+            .await
+            .expect("Failed to establish socket");
+        let token = auth::issue_token(TEST_TOKEN_SECRET.as_bytes(), Id::new(), SESSION_TOKEN_TTL);
+        socket
+            .send(Message::text(
+                json!(OpenSocketRequest { token }).to_string(),
+            ))
+            .await
+            .expect("Failed to send session token");
+
+        server.shutdown().await;
+        let message = tokio::time::timeout(Duration::from_secs(1), socket.next())
+            .await
+            .expect("Timeout waiting for close frame")
+            .expect("No message found")
+            .expect("Failed to read message");
+        let Message::Close(Some(frame)) = message else {
+            panic!("Expected close frame, got {:?}", message);
+        };
+        assert_eq!(CloseCode::Away, frame.code);
+    }
+
+    #[tokio::test]
+    async fn repeated_create_game_is_idempotent() {
+        run_test_case("repeated_create_game.json").await;
     }
 }
@@ -1,15 +1,28 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     extract::{Path, State},
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use common::model::messages::{CreateGameRequest, CreateGameResponse, GetGameResponse, Id};
-use common::reqwest::Url;
+use common::{
+    auth,
+    message::game_server::{ClientRequest, ClientResponse},
+    model::messages::{
+        CreateGameRequest, CreateGameResponse, GameServerHeartbeatRequest, GameServerStatsResponse,
+        GetGameResponse, Id, RegisterGameServerRequest,
+    },
+    reqwest::{Client, Url},
+    utility::url,
+};
 use tokio::{
+    net::TcpListener,
     sync::{
         broadcast,
         mpsc::{self, Receiver},
@@ -18,11 +31,17 @@ use tokio::{
     task::JoinHandle,
 };
 use tower_http::trace::TraceLayer;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
-use crate::{entrypoint::GameServerConfig, model::internal::GameRequest};
+use crate::{
+    entrypoint::GameServerConfig,
+    model::internal::{GameRequest, PlayerHandle},
+};
 
-use super::game_thread::{GameConfiguration, GameThread};
+use super::{
+    game_thread::{GameConfiguration, GameThread},
+    validation::{ApiError, ValidJson},
+};
 
 #[derive(Debug)]
 struct GameHandle {
@@ -30,14 +49,51 @@ struct GameHandle {
     players: (Id, Id),
     to_game: mpsc::Sender<GameRequest>,
     handle: JoinHandle<()>,
+    // Nonces of join tickets already used for this game
+    used_tickets: HashSet<Id>,
 }
 
+// How often finished games are removed from the manager
+const REAP_INTERVAL: Duration = Duration::from_millis(500);
+// How long matchmaking is asked to wait before placing games here again once we're full
+const FULL_RETRY_AFTER: Duration = Duration::from_secs(5);
+
 struct GameManagerState {
     config: GameServerConfig,
     games: HashMap<Id, Arc<Mutex<GameHandle>>>,
     player_assignment: HashMap<Id, Id>,
     shutdown_receiver: broadcast::Receiver<()>,
 }
+
+impl GameManagerState {
+    // Remove games whose thread has exited, freeing their players to join another game
+    async fn reap_finished_games(&mut self) {
+        let mut finished = vec![];
+        for (game_id, game) in self.games.iter() {
+            if game.lock().await.handle.is_finished() {
+                finished.push(*game_id);
+            }
+        }
+
+        for game_id in finished {
+            let Some(game) = self.games.remove(&game_id) else {
+                continue;
+            };
+            let mut game = game.lock().await;
+            for player_id in [game.players.0, game.players.1] {
+                if self.player_assignment.get(&player_id) == Some(&game_id) {
+                    self.player_assignment.remove(&player_id);
+                }
+            }
+            match (&mut game.handle).await {
+                Ok(()) => info!("Reaped finished game {}", game_id),
+                Err(e) if e.is_panic() => error!("Game {} panicked: {:?}", game_id, e),
+                Err(e) => warn!("Game {} was cancelled: {:?}", game_id, e),
+            }
+        }
+    }
+}
+
 pub struct GameManager {}
 
 // TODO: this is a controller. Separate threads into their own "services"? 🤔
@@ -49,6 +105,7 @@ impl GameManager {
     pub async fn run(
         &self,
         config: GameServerConfig,
+        listener: TcpListener,
         shutdown_receiver: &mut broadcast::Receiver<()>,
         from_socket: Receiver<GameRequest>,
     ) {
@@ -59,19 +116,26 @@ impl GameManager {
             player_assignment: HashMap::new(),
             shutdown_receiver: shutdown_receiver.resubscribe(),
         }));
-        // TODO: some sort of collector to cleanup dead games? or threads clean themselves
-
         // Serve REST endpoint
         let rest_state = state.clone(); // TODO: I really want to not have to manually clone these
                                         // before moving :(
         let rest_shutdown_receiver = shutdown_receiver.resubscribe();
         let rest_handle: JoinHandle<()> = tokio::spawn(async move {
-            Self::rest_endpoint_thread(
-                config.manager_address.clone(),
-                rest_state,
-                rest_shutdown_receiver,
-            )
-            .await
+            Self::rest_endpoint_thread(listener, rest_state, rest_shutdown_receiver).await
+        });
+
+        // Spawn thread to clean up finished games
+        let reaper_state = state.clone();
+        let reaper_shutdown_receiver = shutdown_receiver.resubscribe();
+        let reaper_handle: JoinHandle<()> = tokio::spawn(async move {
+            Self::game_reaper_thread(reaper_state, reaper_shutdown_receiver).await;
+        });
+
+        // Spawn thread to register with matchmaking and report our load
+        let heartbeat_state = state.clone();
+        let heartbeat_shutdown_receiver = shutdown_receiver.resubscribe();
+        let heartbeat_handle: JoinHandle<()> = tokio::spawn(async move {
+            Self::heartbeat_thread(heartbeat_state, heartbeat_shutdown_receiver).await;
         });
 
         // Spawn thread to route game messages to game threads
@@ -86,6 +150,126 @@ impl GameManager {
         router_handle
             .await
             .expect("REST endpoint exited non-gracefully");
+        reaper_handle
+            .await
+            .expect("Game reaper exited non-gracefully");
+        heartbeat_handle
+            .await
+            .expect("Heartbeat thread exited non-gracefully");
+    }
+
+    async fn heartbeat_thread(
+        state: Arc<Mutex<GameManagerState>>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) {
+        let config = state.lock().await.config.clone();
+        let Some(matchmaking_url) = config.matchmaking_url.as_ref() else {
+            debug!("No matchmaking url configured, not registering");
+            return;
+        };
+        let Ok(matchmaking_url) = Url::parse(matchmaking_url) else {
+            error!("Invalid matchmaking url {:?}", matchmaking_url);
+            return;
+        };
+        let client = Client::new();
+        let mut registered = false;
+        let mut interval = tokio::time::interval(config.heartbeat_interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    registered = if registered {
+                        Self::send_heartbeat(&state, &client, &matchmaking_url).await
+                    } else {
+                        Self::register(&config, &client, &matchmaking_url).await
+                    };
+                },
+                _ = shutdown_receiver.recv() => {
+                    break;
+                }
+            };
+        }
+    }
+
+    // Tell matchmaking about this server. Returns whether it worked.
+    async fn register(config: &GameServerConfig, client: &Client, matchmaking_url: &Url) -> bool {
+        let request = RegisterGameServerRequest {
+            url: url("http", &config.manager_address, ""),
+            socket_address: config.socket_address.clone(),
+            capacity: config.max_concurrent_games,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        let Ok(endpoint) = matchmaking_url.join("servers/register") else {
+            return false;
+        };
+        let secret = config.token_secret.as_bytes();
+        let response = auth::signed_json(client.post(endpoint), secret, &request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match response {
+            Ok(_) => {
+                info!("Registered with matchmaking at {}", matchmaking_url);
+                true
+            }
+            Err(e) => {
+                warn!("Failed to register with matchmaking: {}", e);
+                false
+            }
+        }
+    }
+
+    // Report our load to matchmaking. Returns whether we're still registered.
+    async fn send_heartbeat(
+        state: &Arc<Mutex<GameManagerState>>,
+        client: &Client,
+        matchmaking_url: &Url,
+    ) -> bool {
+        let (request, secret) = {
+            let mut state = state.lock().await;
+            state.reap_finished_games().await;
+            let request = GameServerHeartbeatRequest {
+                url: url("http", &state.config.manager_address, ""),
+                live_games: state.games.len() as u32,
+            };
+            (request, state.config.token_secret.clone())
+        };
+        let Ok(endpoint) = matchmaking_url.join("servers/heartbeat") else {
+            return false;
+        };
+        let request = auth::signed_json(client.post(endpoint), secret.as_bytes(), &request);
+        match request.send().await {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                warn!("Matchmaking forgot about us, registering again");
+                false
+            }
+            Ok(response) => {
+                if let Err(e) = response.error_for_status() {
+                    warn!("Heartbeat was rejected: {}", e);
+                }
+                true
+            }
+            Err(e) => {
+                warn!("Failed to send heartbeat: {}", e);
+                true
+            }
+        }
+    }
+
+    async fn game_reaper_thread(
+        state: Arc<Mutex<GameManagerState>>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    state.lock().await.reap_finished_games().await;
+                },
+                _ = shutdown_receiver.recv() => {
+                    break;
+                }
+            };
+        }
     }
 
     // Game logic loop
@@ -110,33 +294,73 @@ impl GameManager {
     }
 
     async fn route_request(state: Arc<Mutex<GameManagerState>>, request: GameRequest) {
-        let state = state.lock().await;
-        // Resolve game id by player
-        let player_id = request.player.id;
-        let Some(game_id) = state.player_assignment.get(&player_id) else {
-            warn!("No game found for player {:?}", player_id);
-            debug!("Assignment: {:?}", state.player_assignment);
-            return;
-        };
+        // Only hold the manager while finding the game, so a busy game or a client that isn't
+        // reading holds up nothing but their own request
+        let (game_id, to_game) = {
+            let state = state.lock().await;
+            // Resolve game id by player
+            let player_id = request.player.id;
+            let Some(game_id) = state.player_assignment.get(&player_id) else {
+                warn!("No game found for player {:?}", player_id);
+                debug!("Assignment: {:?}", state.player_assignment);
+                let response = ClientResponse::error("not_in_game", "Not in a game on this server");
+                Self::reply(&request.player, response);
+                return;
+            };
 
-        // Lookup game
-        match state.games.get(game_id) {
-            Some(game) => {
-                let game = game.lock().await;
-                game.to_game.send(request).await.unwrap_or_else(|e| {
-                    panic!(
-                        "Failed to route request to game for player {:?}, game {:?}, err {:?}",
-                        player_id, game.id, e
-                    )
-                });
+            // Lookup game
+            let Some(game) = state.games.get(game_id) else {
+                warn!("No game found for player {:?}", player_id);
+                return;
+            };
+            let mut game = game.lock().await;
+            if let ClientRequest::JoinGame { ticket } = &request.request {
+                let secret = state.config.token_secret.as_bytes();
+                if let Err(reason) = Self::check_ticket(&mut game, secret, player_id, ticket) {
+                    warn!("Rejected join from {:?}: {}", player_id, reason);
+                    Self::reply(&request.player, ClientResponse::JoinRejected { reason });
+                    return;
+                }
             }
-            None => warn!("No game found for player {:?}", player_id),
+            (game.id, game.to_game.clone())
         };
+
+        // The game thread exits when the game ends, before it's reaped
+        if let Err(e) = to_game.send(request).await {
+            warn!("Game {} is over, dropping request", game_id);
+            let response = ClientResponse::error("game_over", "Game has ended");
+            Self::reply(&e.0.player, response);
+        }
+    }
+
+    // Send a reply without waiting for room in the client's channel. A client that stopped
+    // reading misses it.
+    fn reply(player: &PlayerHandle, response: ClientResponse) {
+        if let Err(e) = player.sender.try_send(response) {
+            warn!("Dropped reply to {:?}: {}", player.id, e);
+        }
+    }
+
+    // Accept a join ticket if it was issued to this player for this game and hasn't been used
+    fn check_ticket(
+        game: &mut GameHandle,
+        secret: &[u8],
+        player_id: Id,
+        ticket: &str,
+    ) -> Result<(), String> {
+        let ticket = auth::verify_join_ticket(secret, ticket).map_err(|e| e.to_string())?;
+        if ticket.game_id != game.id || ticket.user_id != player_id {
+            return Err("ticket is for another seat".to_string());
+        }
+        if !game.used_tickets.insert(ticket.nonce) {
+            return Err("ticket already used".to_string());
+        }
+        Ok(())
     }
 
     // REST functions
     async fn rest_endpoint_thread(
-        address: String,
+        listener: TcpListener,
         state: Arc<Mutex<GameManagerState>>,
         mut shutdown_receiver: broadcast::Receiver<()>,
     ) {
@@ -145,11 +369,9 @@ impl GameManager {
             .route("/", get(Self::root))
             .route("/create_game", post(Self::create_game))
             .route("/game/{game_id}", get(Self::get_game))
+            .route("/stats", get(Self::get_stats))
             .with_state(state);
-        let listener = tokio::net::TcpListener::bind(address.clone())
-            .await
-            .unwrap();
-        info!("Game manager listening on {}", address);
+        info!("Game manager listening on {:?}", listener.local_addr());
         axum::serve(listener, app)
             .with_graceful_shutdown(async move {
                 shutdown_receiver
@@ -167,29 +389,75 @@ impl GameManager {
 
     async fn create_game(
         State(state): State<Arc<Mutex<GameManagerState>>>,
-        Json(request): Json<CreateGameRequest>,
+        ValidJson(request): ValidJson<CreateGameRequest>,
     ) -> Response {
-        // TODO:
         let mut state = state.lock_owned().await;
-        // Unpack player IDs
-        let [player_1, player_2] = request.players.as_slice() else {
-            panic!("Expected 2 player IDs")
-        };
-        let (player_1, player_2) = (*player_1, *player_2);
-        // Check if players are already in a game
+        let game_id = request.game_id;
+        let (player_1, player_2) = request.players;
+        // A repeated request, e.g. matchmaking retrying after a timeout, gets the existing game
+        if let Some(game) = state.games.get(&game_id) {
+            if game.lock().await.players != request.players {
+                return ApiError::new(
+                    StatusCode::CONFLICT,
+                    "game_id_conflict",
+                    format!("Game {} already exists with other players", game_id),
+                )
+                .into_response();
+            }
+            info!("Game {} already exists", game_id);
+            return (
+                StatusCode::OK,
+                Json(CreateGameResponse {
+                    game_id,
+                    address: state.config.socket_address.clone(),
+                }),
+            )
+                .into_response();
+        }
+        // Check if players are already in a game, ignoring games that have since finished
+        state.reap_finished_games().await;
+        if state
+            .config
+            .max_concurrent_games
+            .is_some_and(|max| state.games.len() as u32 >= max)
+        {
+            warn!(
+                "At capacity with {} games, refusing new game",
+                state.games.len()
+            );
+            let error = ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_full",
+                "Server is at capacity",
+            );
+            return (
+                [(RETRY_AFTER, FULL_RETRY_AFTER.as_secs().to_string())],
+                error,
+            )
+                .into_response();
+        }
         if state.player_assignment.contains_key(&player_1)
             || state.player_assignment.contains_key(&player_2)
         {
-            return (StatusCode::CONFLICT, "A player is already in a game").into_response();
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "player_in_game",
+                "A player is already in a game",
+            )
+            .into_response();
         }
         // Create game config
         let configuration = GameConfiguration {
+            game_id,
             players: (player_1, player_2),
             games_to_win: request.games_to_win,
+            matchmaking_url: state.config.matchmaking_url.clone(),
+            move_timeout: state.config.move_timeout,
+            join_timeout: state.config.join_timeout,
+            token_secret: state.config.token_secret.clone(),
         };
 
         // Insert new game
-        let game_id = Id::new();
         let (to_game, from_socket) = mpsc::channel(100); // TODO:
                                                          // what's the size here
 
@@ -211,6 +479,7 @@ impl GameManager {
                 players: (player_1, player_2),
                 to_game,
                 handle,
+                used_tickets: HashSet::new(),
             })),
         );
         debug!(
@@ -228,6 +497,19 @@ impl GameManager {
             .into_response()
     }
 
+    // Load of this server, for matchmaking to decide where to place games
+    async fn get_stats(State(state): State<Arc<Mutex<GameManagerState>>>) -> Response {
+        let mut state = state.lock().await;
+        state.reap_finished_games().await;
+        (
+            StatusCode::OK,
+            Json(GameServerStatsResponse {
+                live_games: state.games.len() as u32,
+            }),
+        )
+            .into_response()
+    }
+
     async fn get_game(
         Path(game_id): Path<Id>,
         State(state): State<Arc<Mutex<GameManagerState>>>,
@@ -3,7 +3,7 @@ use async_trait::async_trait;
 use common::{
     message::game_server::{ClientRequest, ClientResponse},
     model::messages::Id,
-    websocket::WebsocketHandler,
+    websocket::{WebsocketError, WebsocketHandler},
 };
 use tokio::sync::mpsc::Sender;
 pub struct GameSocket {}
@@ -25,7 +25,7 @@ impl WebsocketHandler<ClientRequest, ClientResponse, GameRequest> for GameSocket
         request: ClientRequest,
         to_user_sender: Sender<ClientResponse>,
         internal_sender: Sender<GameRequest>,
-    ) -> Option<ClientResponse> {
+    ) -> Result<Option<ClientResponse>, WebsocketError> {
         // Resolve player object and route to game manager
         let request = GameRequest {
             player: PlayerHandle {
@@ -34,11 +34,12 @@ impl WebsocketHandler<ClientRequest, ClientResponse, GameRequest> for GameSocket
             },
             request,
         };
+        // The game manager only stops listening when the server is going down
         internal_sender
             .send(request)
             .await
-            .expect("Failed to send internal message");
-        None
+            .map_err(|_| WebsocketError::Internal("Game manager is unavailable".to_owned()))?;
+        Ok(None)
     }
 
     fn drop_after_send(response: ClientResponse) -> bool {
@@ -48,7 +49,8 @@ impl WebsocketHandler<ClientRequest, ClientResponse, GameRequest> for GameSocket
                 result: _,
                 wins: _,
                 total: _,
-            }
+            } | ClientResponse::GameCancelled { reason: _ }
+                | ClientResponse::JoinRejected { reason: _ }
         )
     }
 }
@@ -1,25 +1,53 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use common::{
+    auth,
     message::game_server::{ClientRequest, ClientResponse, RoundResultResponse},
     model::{
         game::{self, Move},
-        messages::Id,
+        messages::{Id, PostGameAbandonedRequest, PostGameResultsRequest},
     },
+    reqwest::{Client, Url},
+    utility::{self, Backoff},
 };
 use itertools::Itertools;
-use tokio::sync::{
-    broadcast,
-    mpsc::{self, Receiver},
+use serde::Serialize;
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self, Receiver},
+    },
+    time::Instant,
 };
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::model::internal::{GameRequest, PlayerHandle};
 
+// Retries for reporting a finished or abandoned game to matchmaking. More patient than game
+// creation, since nobody is waiting on the report but the outcome would be lost without it.
+const REPORT_BACKOFF: Backoff = Backoff {
+    attempts: 5,
+    initial_delay: Duration::from_millis(200),
+};
+// Time allowed to connect to matchmaking, and for a whole report
+const REPORT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REPORT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct GameConfiguration {
+    pub game_id: Id,
     pub players: (Id, Id),
     pub games_to_win: u8,
+    pub matchmaking_url: Option<String>,
+    // Time each player has to submit a move once prompted. None waits forever.
+    pub move_timeout: Option<Duration>,
+    // Time both players have to join once the game is created. None waits forever.
+    pub join_timeout: Option<Duration>,
+    // Secret reports to matchmaking are signed with
+    pub token_secret: String,
 }
 
 struct Player {
@@ -36,17 +64,31 @@ impl Player {
             wins: 0,
         }
     }
+
+    // Best-effort send. A player whose socket closed misses the message, and is caught up when
+    // they reconnect.
+    async fn notify(&self, response: ClientResponse) {
+        let _ = self
+            .sender
+            .send(response)
+            .await
+            .inspect_err(|e| warn!("Failed to notify {}: {}", self.id, e));
+    }
 }
 
 struct GameState {
     phase: GamePhase,
     configuration: GameConfiguration,
-    rounds_played: u8,
+    rounds_played: u32,
     players: HashMap<Id, Player>,
+    deadline: Option<Instant>,
 }
 
 impl GameState {
     pub fn new(configuration: GameConfiguration) -> Self {
+        let deadline = configuration
+            .join_timeout
+            .map(|timeout| Instant::now() + timeout);
         GameState {
             phase: GamePhase::WaitingForPlayers {
                 connected: HashSet::new(),
@@ -54,27 +96,30 @@ impl GameState {
             configuration,
             rounds_played: 0,
             players: HashMap::new(),
+            deadline,
         }
     }
 
     pub async fn update(&mut self, request: GameRequest) {
         let player_id = request.player.id;
+        let started = !matches!(self.phase, GamePhase::WaitingForPlayers { .. });
+        if started && matches!(request.request, ClientRequest::JoinGame { .. }) {
+            self.reconnect(request.player).await;
+            return;
+        }
         match self.phase {
             GamePhase::WaitingForPlayers { ref connected } => {
                 let mut connected = connected.clone();
                 match request.request {
-                    ClientRequest::JoinGame => {
+                    ClientRequest::JoinGame { .. } => {
                         connected.insert(player_id);
                         let player = Player::from(request.player);
-                        player
-                            .sender
-                            .send(ClientResponse::GameJoined)
-                            .await
-                            .expect("Failed to send client response");
+                        player.notify(ClientResponse::GameJoined).await;
                         self.players.insert(player_id, player);
                     }
                     _ => {
-                        warn!("Got non-JoinGame message in WaitingForPlayers phase");
+                        let message = "Both players have to join before moves are made";
+                        Self::refuse(&request.player, "game_not_started", message);
                         return;
                     }
                 }
@@ -85,13 +130,7 @@ impl GameState {
 
                 // Players are ready, prompt for moves
                 debug!("All players connected, notifying.");
-                for player in self.players.values() {
-                    player
-                        .sender
-                        .send(ClientResponse::PendingMove)
-                        .await
-                        .expect("Failed to send message to player");
-                }
+                self.prompt_moves().await;
                 self.phase = GamePhase::PendingMoves {
                     moves: HashMap::new(),
                 };
@@ -99,15 +138,13 @@ impl GameState {
             GamePhase::PendingMoves { ref moves } => {
                 // TODO: can we just modify moves as mut? pls
                 let mut moves = moves.clone();
+                // JoinGame after the start is a reconnect, handled above, so this is a Move
                 let ClientRequest::Move { value } = request.request else {
-                    warn!("Got non-Move message in PendingMoves phase");
                     return;
                 };
                 if moves.contains_key(&player_id) {
-                    warn!(
-                        "Player {} submitted a move but already has a move present.",
-                        player_id
-                    );
+                    let message = "Already moved this round";
+                    Self::refuse(&request.player, "move_already_submitted", message);
                     return;
                 }
 
@@ -125,40 +162,180 @@ impl GameState {
                     .expect("Expected two player-move pairs");
 
                 // Update self
-                match Self::get_winner(player_1, player_2) {
-                    Some(winner_id) => {
-                        let winner = self.players.get_mut(&winner_id).expect("Winner not found");
-                        winner.wins += 1;
-                        self.rounds_played += 1;
-
-                        let config = self.configuration.clone();
-                        if winner.wins >= config.games_to_win {
-                            self.notify_round_result(winner_id, moves).await;
-                            self.notify_match_result(winner_id).await;
-                            self.phase = GamePhase::Done;
-                            return;
-                        } else {
-                            self.notify_round_result(winner_id, moves).await;
-                        }
-                    }
-                    None => {
-                        self.rounds_played += 1;
-                    }
-                };
+                let winner = Self::get_winner(player_1, player_2);
+                let match_over = self.finish_round(winner);
+                self.notify_round_result(winner, moves).await;
+                if match_over {
+                    self.end_match(winner).await;
+                    return;
+                }
+                self.prompt_moves().await;
                 self.phase = GamePhase::PendingMoves {
                     moves: HashMap::new(),
                 };
             }
-            GamePhase::Done => {
-                warn!(
-                    "Got request {:?} even though game is in Done state",
-                    request
-                );
+            GamePhase::Done | GamePhase::Cancelled => {
+                Self::refuse(&request.player, "game_over", "Game has ended");
             }
         }
     }
 
-    async fn notify_round_result(&self, winner: Id, moves: HashMap<Id, Move>) {
+    // Tell a player their request was refused. They may have disconnected since sending it, and
+    // if they've stopped reading the refusal is dropped rather than holding up the game.
+    fn refuse(handle: &PlayerHandle, code: &str, message: &str) {
+        warn!("Refused request from {}: {}", handle.id, message);
+        let _ = handle
+            .sender
+            .try_send(ClientResponse::error(code, message))
+            .inspect_err(|e| warn!("Failed to notify {} of refusal: {}", handle.id, e));
+    }
+
+    // Swap in a rejoining player's new connection, and catch them up on the game's state
+    async fn reconnect(&mut self, handle: PlayerHandle) {
+        let move_pending = match self.phase {
+            GamePhase::PendingMoves { ref moves } => !moves.contains_key(&handle.id),
+            _ => false,
+        };
+        let opponent_wins = self
+            .players
+            .values()
+            .find(|other| other.id != handle.id)
+            .map_or(0, |other| other.wins);
+        let Some(player) = self.players.get_mut(&handle.id) else {
+            warn!(
+                "Player {} tried to rejoin a game they never joined",
+                handle.id
+            );
+            return;
+        };
+        info!("Player {} reconnected", handle.id);
+        player.sender = handle.sender;
+        player
+            .notify(ClientResponse::Reconnected {
+                round: self.rounds_played + 1,
+                wins: player.wins,
+                opponent_wins,
+                move_pending,
+            })
+            .await;
+    }
+
+    // Handle the current phase's deadline passing
+    pub async fn handle_deadline(&mut self) {
+        self.deadline = None;
+        match self.phase {
+            GamePhase::WaitingForPlayers { .. } => self.handle_join_timeout().await,
+            GamePhase::PendingMoves { .. } => self.handle_move_timeout().await,
+            GamePhase::Done | GamePhase::Cancelled => {}
+        }
+    }
+
+    // Cancel the game when a player never joined, letting anyone who did join know
+    async fn handle_join_timeout(&mut self) {
+        info!(
+            "Cancelling game {}, only {:?} joined in time",
+            self.configuration.game_id,
+            self.players.keys()
+        );
+        for player in self.players.values() {
+            player
+                .notify(ClientResponse::GameCancelled {
+                    reason: "Opponent didn't join the game in time".to_owned(),
+                })
+                .await;
+        }
+        self.phase = GamePhase::Cancelled;
+    }
+
+    // A player who missed the move deadline loses the round, and if both players missed it the
+    // match ends in a draw.
+    async fn handle_move_timeout(&mut self) {
+        let GamePhase::PendingMoves { ref moves } = self.phase else {
+            return;
+        };
+        let missed: HashSet<Id> = self
+            .players
+            .keys()
+            .filter(|id| !moves.contains_key(id))
+            .copied()
+            .collect();
+        if missed.is_empty() {
+            return;
+        }
+        info!("Players {:?} missed the move deadline", missed);
+
+        let winner = match missed.len() {
+            1 => self.players.keys().find(|id| !missed.contains(id)).copied(),
+            _ => None,
+        };
+        let match_over = self.finish_round(winner) || winner.is_none();
+        for player in self.players.values() {
+            let result = match winner {
+                Some(winner) if player.id == winner => game::Outcome::Win,
+                Some(_) => game::Outcome::Loss,
+                None => game::Outcome::Draw,
+            };
+            let opponent_missed = missed.iter().any(|id| *id != player.id);
+            player
+                .notify(ClientResponse::MoveTimeout {
+                    result,
+                    missed: missed.contains(&player.id),
+                    opponent_missed,
+                })
+                .await;
+        }
+
+        if match_over {
+            self.end_match(winner).await;
+            return;
+        }
+        self.prompt_moves().await;
+        self.phase = GamePhase::PendingMoves {
+            moves: HashMap::new(),
+        };
+    }
+
+    // Record the outcome of a round, returning whether the winner has now won the match
+    fn finish_round(&mut self, winner: Option<Id>) -> bool {
+        self.rounds_played += 1;
+        let Some(winner) = winner else {
+            return false;
+        };
+        let winner = self.players.get_mut(&winner).expect("Winner not found");
+        winner.wins += 1;
+        winner.wins >= self.configuration.games_to_win
+    }
+
+    async fn end_match(&mut self, winner: Option<Id>) {
+        self.notify_match_result(winner).await;
+        self.deadline = None;
+        self.phase = GamePhase::Done;
+    }
+
+    // Prompt each player for their move in the upcoming round
+    async fn prompt_moves(&mut self) {
+        self.deadline = self
+            .configuration
+            .move_timeout
+            .map(|timeout| Instant::now() + timeout);
+        for player in self.players.values() {
+            let opponent_wins = self
+                .players
+                .values()
+                .find(|other| other.id != player.id)
+                .map_or(0, |other| other.wins);
+            player
+                .notify(ClientResponse::PendingMove {
+                    round: self.rounds_played + 1,
+                    wins: player.wins,
+                    opponent_wins,
+                })
+                .await;
+        }
+    }
+
+    // Notify each player of the round's outcome. A winner of None means the round was a draw.
+    async fn notify_round_result(&self, winner: Option<Id>, moves: HashMap<Id, Move>) {
         for player in self.players.values() {
             let other_move = moves
                 .iter()
@@ -167,37 +344,53 @@ impl GameState {
                 .expect("Other player's move not found")
                 .clone();
 
+            let result = match winner {
+                Some(winner) if player.id == winner => game::Outcome::Win,
+                Some(_) => game::Outcome::Loss,
+                None => game::Outcome::Draw,
+            };
             player
-                .sender
-                .send(match player.id == winner {
-                    true => ClientResponse::RoundResult(RoundResultResponse {
-                        result: game::Outcome::Win,
-                        other_move,
-                    }),
-                    false => ClientResponse::RoundResult(RoundResultResponse {
-                        result: game::Outcome::Loss,
-                        other_move,
-                    }),
-                })
-                .await
-                .expect("Failed to notify round result");
+                .notify(ClientResponse::RoundResult(RoundResultResponse {
+                    result,
+                    other_move,
+                }))
+                .await;
         }
     }
-    async fn notify_match_result(&self, winner: Id) {
+    // Notify each player of the match's outcome. A winner of None means the match was a draw.
+    async fn notify_match_result(&self, winner: Option<Id>) {
         for player in self.players.values() {
-            let result = match player.id == winner {
-                true => game::Outcome::Win,
-                false => game::Outcome::Loss,
+            let result = match winner {
+                Some(winner) if player.id == winner => game::Outcome::Win,
+                Some(_) => game::Outcome::Loss,
+                None => game::Outcome::Draw,
             };
             player
-                .sender
-                .send(ClientResponse::MatchResult {
+                .notify(ClientResponse::MatchResult {
                     result,
                     wins: player.wins,
                     total: self.rounds_played,
                 })
-                .await
-                .expect("Failed to notify round result");
+                .await;
+        }
+    }
+
+    fn results(&self) -> PostGameResultsRequest {
+        let (player_1, player_2) = self.configuration.players;
+        let wins = |id: Id| self.players.get(&id).map_or(0, |player| player.wins);
+        PostGameResultsRequest {
+            game_id: self.configuration.game_id,
+            players: (player_1, player_2),
+            games_to_win: self.configuration.games_to_win,
+            games_won: (wins(player_1), wins(player_2)),
+        }
+    }
+
+    fn abandonment(&self) -> PostGameAbandonedRequest {
+        PostGameAbandonedRequest {
+            game_id: self.configuration.game_id,
+            players: self.configuration.players,
+            joined: self.players.keys().copied().collect(),
         }
     }
 
@@ -222,6 +415,8 @@ enum GamePhase {
     WaitingForPlayers { connected: HashSet<Id> },
     PendingMoves { moves: HashMap<Id, Move> },
     Done,
+    // Game ended before it started, e.g. a player never joined
+    Cancelled,
 }
 
 pub struct GameThread {}
@@ -235,20 +430,85 @@ impl GameThread {
         let mut state = GameState::new(configuration.clone());
         let mut shutdown_receiver = shutdown_receiver.resubscribe();
         loop {
+            let deadline = state.deadline;
             tokio::select! {
                 request = from_socket.recv() => {
                     if let Some(request) = request {
                         state.update(request).await;
                     }
-                    if matches!(state.phase, GamePhase::Done) {
-                        info!("Game over, exiting");
-                        break;
-                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => {
+                    state.handle_deadline().await;
                 }
                 _ = shutdown_receiver.recv() => {
                     break;
                 }
             }
+            if matches!(state.phase, GamePhase::Done | GamePhase::Cancelled) {
+                info!("Game over, exiting");
+                break;
+            }
+        }
+
+        match state.phase {
+            GamePhase::Done => {
+                Self::report(&configuration, "game/result", &state.results()).await;
+            }
+            GamePhase::Cancelled => {
+                Self::report(&configuration, "game/abandoned", &state.abandonment()).await;
+            }
+            _ => {}
+        }
+    }
+
+    // POST the game's outcome to matchmaking, retrying with exponential backoff
+    async fn report<T: Serialize>(configuration: &GameConfiguration, endpoint: &str, body: &T) {
+        let game_id = configuration.game_id;
+        let Some(matchmaking_url) = &configuration.matchmaking_url else {
+            debug!(
+                "No matchmaking url configured, not reporting game {}",
+                game_id
+            );
+            return;
+        };
+        let url = match Url::parse(matchmaking_url).and_then(|url| url.join(endpoint)) {
+            Ok(url) => url,
+            Err(e) => {
+                error!("Invalid matchmaking url {:?}: {}", matchmaking_url, e);
+                return;
+            }
+        };
+
+        // Without timeouts a hung matchmaking server would keep this game from ever being reaped
+        let client = match Client::builder()
+            .connect_timeout(REPORT_CONNECT_TIMEOUT)
+            .timeout(REPORT_REQUEST_TIMEOUT)
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to build client to report game {}: {}", game_id, e);
+                return;
+            }
+        };
+        let (client, url) = (&client, &url);
+        let secret = configuration.token_secret.as_bytes();
+        let send_report = |attempt| async move {
+            auth::signed_json(client.post(url.clone()), secret, body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .inspect_err(|e| {
+                    warn!(
+                        "Failed to report game {} to {} (attempt {}/{}): {}",
+                        game_id, url, attempt, REPORT_BACKOFF.attempts, e
+                    )
+                })
+        };
+        match utility::retry(REPORT_BACKOFF, send_report, |_| true).await {
+            Ok(_) => info!("Reported game {} to {}", game_id, url),
+            Err(_) => error!("Giving up on reporting game {} to {}", game_id, url),
         }
     }
 }
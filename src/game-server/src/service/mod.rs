@@ -1,3 +1,4 @@
 pub mod game_manager;
 pub mod game_socket;
 mod game_thread;
+pub mod validation;
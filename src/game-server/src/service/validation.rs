@@ -0,0 +1,90 @@
+use axum::{
+    extract::{FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use common::model::messages::{CreateGameRequest, ErrorResponse};
+use serde::de::DeserializeOwned;
+
+// Error returned by the REST API, rendered as a structured JSON body
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    // Request was well formed, but asks for something that doesn't make sense
+    pub fn unprocessable(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, code, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            code: self.code.to_string(),
+            message: self.message,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+// Checks on a request that deserializing alone can't express
+pub trait Validate {
+    fn validate(&self) -> Result<(), ApiError>;
+}
+
+// JSON extractor that rejects bodies it can't parse with a 400, and bodies that fail validation
+// with a 422
+pub struct ValidJson<T>(pub T);
+
+impl<S, T> FromRequest<S> for ValidJson<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Validate,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(|rejection| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_body",
+                    rejection.body_text(),
+                )
+            })?;
+        value.validate()?;
+        Ok(ValidJson(value))
+    }
+}
+
+impl Validate for CreateGameRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.games_to_win == 0 {
+            return Err(ApiError::unprocessable(
+                "invalid_games_to_win",
+                "games_to_win must be at least 1",
+            ));
+        }
+        if self.players.0 == self.players.1 {
+            return Err(ApiError::unprocessable(
+                "duplicate_players",
+                format!("Player {} can't play against themselves", self.players.0),
+            ));
+        }
+        Ok(())
+    }
+}
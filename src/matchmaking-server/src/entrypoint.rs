@@ -1,6 +1,8 @@
 use super::service::{matchmaking::MatchmakingService, queue_socket::QueueSocket};
 use common::utility::Channel;
-use common::websocket::WebsocketHandler;
+use common::websocket::{SocketConfig, WebsocketHandler};
+use std::{io, time::Duration};
+use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::{info, Level};
@@ -10,11 +12,42 @@ pub struct MatchmakingConfig {
     pub socket_address: String,
     pub rest_address: String,
     pub db_url: String,
-    pub game_server_url: String,
+    // Game servers to place games on, before any register themselves
+    pub game_server_urls: Vec<String>,
+    // How often queued players are sent their queue status
+    pub queue_ping_interval: Duration,
+    // Registered game servers are evicted after going this long without a heartbeat
+    pub server_timeout: Duration,
+    // Secret session tokens are signed with
+    pub token_secret: String,
+    // Time clients have to identify themselves after opening a socket
+    pub handshake_timeout: Duration,
+    // How long a player whose opponent never joined keeps their place at the front of the queue
+    pub abandoned_priority_window: Duration,
+    // Longest players are sent back to a game they were matched into. Covers the game server's
+    // join deadline plus the longest a game runs, in case the result is never reported.
+    pub active_game_timeout: Duration,
+}
+
+// Listeners for the REST api and the websocket, bound before the server starts
+pub struct MatchmakingListeners {
+    pub rest: TcpListener,
+    pub socket: TcpListener,
+}
+
+impl MatchmakingListeners {
+    // Bind the addresses in the config
+    pub async fn bind(config: &MatchmakingConfig) -> io::Result<Self> {
+        Ok(MatchmakingListeners {
+            rest: TcpListener::bind(&config.rest_address).await?,
+            socket: TcpListener::bind(&config.socket_address).await?,
+        })
+    }
 }
 
 pub async fn serve(
     config: MatchmakingConfig,
+    listeners: MatchmakingListeners,
     shutdown_receiver: tokio::sync::broadcast::Receiver<()>,
     ready_signal: Option<tokio::sync::oneshot::Sender<()>>,
 ) {
@@ -28,13 +61,22 @@ pub async fn serve(
     let config_mm = config.clone();
     let matchmaker_handle: JoinHandle<()> = tokio::spawn(async move {
         MatchmakingService::new()
-            .run(config_mm, &mut mm_shutdown_receiver, to_mm_channel.receiver)
+            .run(
+                config_mm,
+                listeners.rest,
+                &mut mm_shutdown_receiver,
+                to_mm_channel.receiver,
+            )
             .await
     });
     let websocket_handle: JoinHandle<()> = tokio::spawn(async move {
         QueueSocket::new()
             .listen(
-                config.socket_address.clone(),
+                listeners.socket,
+                SocketConfig {
+                    token_secret: config.token_secret.clone(),
+                    handshake_timeout: config.handshake_timeout,
+                },
                 &mut ws_shutdown_receiver,
                 to_mm_channel.sender,
             )
@@ -61,7 +103,7 @@ pub struct MatchmakingServer {
 }
 
 impl MatchmakingServer {
-    pub async fn new(config: MatchmakingConfig) -> Self {
+    pub async fn new(config: MatchmakingConfig, listeners: MatchmakingListeners) -> Self {
         // Init logging, ignore error if already set
         let _ = tracing_subscriber::fmt()
             .with_line_number(true)
@@ -74,7 +116,12 @@ impl MatchmakingServer {
         let (ready_sender, ready_receiver) = tokio::sync::oneshot::channel::<()>();
 
         let moved_cfg = config.clone();
-        tokio::spawn(serve(moved_cfg, shutdown_receiver, Some(ready_sender)));
+        tokio::spawn(serve(
+            moved_cfg,
+            listeners,
+            shutdown_receiver,
+            Some(ready_sender),
+        ));
 
         // Wait for server to be ready
         ready_receiver.await.expect("Server failed to start");
@@ -1,6 +1,7 @@
-use common::utility::create_shutdown_channel;
-use matchmaking_server::entrypoint::{self, MatchmakingConfig};
-use tracing::Level;
+use common::{auth, utility::create_shutdown_channel, websocket::DEFAULT_HANDSHAKE_TIMEOUT};
+use matchmaking_server::entrypoint::{self, MatchmakingConfig, MatchmakingListeners};
+use std::time::Duration;
+use tracing::{error, Level};
 
 #[tokio::main]
 async fn main() {
@@ -9,43 +10,58 @@ async fn main() {
         .with_file(true)
         .with_max_level(Level::DEBUG)
         .init();
+    let token_secret = auth::token_secret_from_env().unwrap_or_else(|e| {
+        error!("Can't start matchmaking: {}", e);
+        std::process::exit(1);
+    });
     // Config
     let config = MatchmakingConfig {
         socket_address: "0.0.0.0:3001".to_owned(),
         rest_address: "0.0.0.0:8081".to_owned(),
-        game_server_url: "http://0.0.0.0:8082".to_owned(),
+        game_server_urls: vec!["http://0.0.0.0:8082".to_owned()],
         db_url: "matchmaking.db".to_owned(),
+        queue_ping_interval: Duration::from_secs(5),
+        server_timeout: Duration::from_secs(15),
+        token_secret,
+        handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        abandoned_priority_window: Duration::from_secs(5 * 60),
+        active_game_timeout: Duration::from_secs(60 * 60),
     };
     let shutdown_receiver = create_shutdown_channel().await;
-    entrypoint::serve(config, shutdown_receiver, None).await;
+    let listeners = MatchmakingListeners::bind(&config)
+        .await
+        .expect("Failed to bind matchmaking addresses");
+    entrypoint::serve(config, listeners, shutdown_receiver, None).await;
 }
 
 #[cfg(test)]
 mod tests {
+    use common::message::game_server::{
+        ClientRequest as GameClientRequest, ClientResponse as GameClientResponse,
+    };
+    use common::reqwest::{
+        header::{ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN},
+        Client, Method, StatusCode,
+    };
     use common::test::DummyType;
     use common::{
-        model::messages::Id,
-        test::{ServerAddress, TestCase},
+        model::messages::{
+            CreateGameRequest, GameServerHeartbeatRequest, GameServerInfo, GameServerStatsResponse,
+            Id, PostGameAbandonedRequest, PostGameResultsRequest, RegisterGameServerRequest,
+            SessionRequest, SessionResponse,
+        },
+        test::{
+            bad_token_replacements, local_listener, ServerAddress, TestCase, TEST_TOKEN_SECRET,
+        },
     };
     use entrypoint::MatchmakingServer;
-    use game_server::entrypoint::{GameServer, GameServerConfig};
+    use game_server::entrypoint::{GameServer, GameServerConfig, GameServerListeners};
     use matchmaking_server::model::messages::{ClientRequest, ClientResponse};
-    use rusqlite::Connection;
+    use rusqlite::{Connection, OptionalExtension};
     use std::collections::HashMap;
     use std::fs;
-    use tokio::net::UdpSocket;
-    use tracing::debug;
 
     use super::*;
-    async fn random_address() -> String {
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .expect("Failed to get random port");
-        socket
-            .local_addr()
-            .expect("Failed to unwrap local address")
-            .to_string()
-    }
 
     fn url<A, B, C>(protocol: A, base_url: B, endpoint: C) -> String
     where
@@ -81,62 +97,911 @@ mod tests {
         db_url
     }
 
-    #[tokio::test]
-    async fn run_game() {
-        // Initialize test database with schema
-        let db_url = init_test_db().await;
+    // Matchmaking on local ports with the listeners already bound to them, configured with a
+    // game server url nothing listens at
+    async fn make_config(db_url: String) -> (MatchmakingConfig, MatchmakingListeners) {
+        let (socket, socket_address) = local_listener().await;
+        let (rest, rest_address) = local_listener().await;
+        // Dropping the listener closes its port again
+        let (_, closed_address) = local_listener().await;
+        let config = MatchmakingConfig {
+            socket_address,
+            rest_address,
+            game_server_urls: vec![url("http", closed_address, "")],
+            db_url,
+            queue_ping_interval: Duration::from_secs(60),
+            server_timeout: Duration::from_secs(60),
+            token_secret: TEST_TOKEN_SECRET.to_owned(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            abandoned_priority_window: Duration::from_secs(60),
+            active_game_timeout: Duration::from_secs(60),
+        };
+        (config, MatchmakingListeners { rest, socket })
+    }
+
+    // Game server on local ports that doesn't register with matchmaking, with the listeners
+    // already bound
+    async fn make_game_server_config() -> (GameServerConfig, GameServerListeners) {
+        let (manager, manager_address) = local_listener().await;
+        let (socket, socket_address) = local_listener().await;
+        let config = GameServerConfig {
+            manager_address,
+            socket_address,
+            matchmaking_url: None,
+            heartbeat_interval: Duration::from_secs(1),
+            max_concurrent_games: None,
+            move_timeout: None,
+            join_timeout: None,
+            token_secret: TEST_TOKEN_SECRET.to_owned(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        };
+        (config, GameServerListeners { manager, socket })
+    }
+
+    // Run a scenario against fresh servers. Each id is bound to ${userN}, and gets a "userN"
+    // socket plus a "userN_reconnect" socket for reconnecting after the first is closed.
+    async fn run_test_case(file_name: &str, ids: &[Id], db_url: String) {
+        // Long enough that periodic queue pings never interleave with a scenario
+        run_test_case_with_ping_interval(file_name, ids, db_url, Duration::from_secs(60)).await;
+    }
+
+    async fn run_test_case_with_ping_interval(
+        file_name: &str,
+        ids: &[Id],
+        db_url: String,
+        queue_ping_interval: Duration,
+    ) {
+        let (mm_server, game_server) = start_servers(db_url, queue_ping_interval).await;
+        run_scenario(
+            file_name,
+            ids,
+            &mm_server,
+            &game_server.config.socket_address,
+        )
+        .await;
+        mm_server.shutdown().await;
+        game_server.shutdown().await;
+    }
 
-        // Stand up servers
+    // Stand up a matchmaking server and a game server that know about each other
+    async fn start_servers(
+        db_url: String,
+        queue_ping_interval: Duration,
+    ) -> (MatchmakingServer, GameServer) {
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let (gs_config, gs_listeners) = make_game_server_config().await;
         let gs_config = GameServerConfig {
-            manager_address: random_address().await,
-            socket_address: random_address().await,
+            matchmaking_url: Some(url("http", mm_config.rest_address.clone(), "")),
+            ..gs_config
         };
         let mm_config = MatchmakingConfig {
-            socket_address: random_address().await,
-            rest_address: random_address().await,
-            game_server_url: url("http", gs_config.manager_address.clone(), ""), // TODO:
-            // should probably use a Url object for this field
-            db_url,
+            game_server_urls: vec![url("http", gs_config.manager_address.clone(), "")],
+            queue_ping_interval,
+            ..mm_config
         };
-        let mm_server = MatchmakingServer::new(mm_config).await;
-
-        let game_server = GameServer::new(gs_config.clone()).await;
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let game_server = GameServer::new(gs_config, gs_listeners).await;
+        (mm_server, game_server)
+    }
 
-        // Set up test case
-        let file_path =
-            env!("CARGO_MANIFEST_DIR").to_string() + "/test/data/queue_multiple_times.json";
-        let ids = [Id::new(), Id::new()];
-        let replacements: Vec<(String, String)> = vec![
-            ("user1".to_string(), ids[0].to_string()),
-            ("user2".to_string(), ids[1].to_string()),
+    // Run a scenario against a matchmaking server that's already up, returning what it captured
+    async fn run_scenario(
+        file_name: &str,
+        ids: &[Id],
+        mm_server: &MatchmakingServer,
+        game_server_address: &str,
+    ) -> HashMap<String, String> {
+        let file_path = env!("CARGO_MANIFEST_DIR").to_string() + "/test/data/" + file_name;
+        let mut replacements: Vec<(String, String)> = vec![
             ("game_id".to_string(), Id::new().to_string()),
             (
                 "game_server_address".to_string(),
-                gs_config.socket_address.clone(),
+                game_server_address.to_string(),
             ),
         ];
+        let socket_url = url("ws", mm_server.config.socket_address.clone(), "");
+        let mut address_lookup = HashMap::from([(
+            "rest".to_string(),
+            ServerAddress::RestApi(url("http", mm_server.config.rest_address.clone(), "")),
+        )]);
+        for (i, id) in ids.iter().enumerate() {
+            let name = format!("user{}", i + 1);
+            replacements.push((name.clone(), id.to_string()));
+            replacements.extend(bad_token_replacements(&name, *id));
+            address_lookup.insert(
+                format!("{}_reconnect", name),
+                ServerAddress::WebSocket(socket_url.clone()),
+            );
+            address_lookup.insert(name, ServerAddress::WebSocket(socket_url.clone()));
+        }
         let test_case = TestCase::<ClientRequest, ClientResponse, DummyType, DummyType>::load(
             file_path,
             replacements,
         );
 
+        test_case.run(address_lookup).await
+    }
+
+    #[tokio::test]
+    async fn run_game() {
+        // Initialize test database with schema
+        let db_url = init_test_db().await;
+        run_test_case("queue_multiple_times.json", &[Id::new(), Id::new()], db_url).await;
+    }
+
+    #[tokio::test]
+    async fn pairs_players_by_rating() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new(), Id::new()];
+        let connection = Connection::open(&db_url).expect("Failed to open test database");
+        for (id, rating) in ids.iter().zip([1000.0, 1050.0, 1600.0]) {
+            connection
+                .execute(
+                    "INSERT INTO players (id, rating) VALUES (?1, ?2)",
+                    (id.to_string(), rating),
+                )
+                .expect("Failed to insert player");
+        }
+        run_test_case("rating_aware_pairing.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn get_server_returns_active_game() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new(), Id::new()];
+        run_test_case("get_server.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn users_in_a_game_cannot_queue() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new()];
+        run_test_case("queue_while_in_game.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn users_who_leave_are_not_matched() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new(), Id::new()];
+        run_test_case("leave_queue.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn users_can_rejoin_after_leaving() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new()];
+        run_test_case("rejoin_queue.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn ping_reports_queue_status() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new(), Id::new()];
+        run_test_case("queue_status.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn sockets_with_invalid_tokens_are_closed() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new()];
+        run_test_case("invalid_token.json", &ids, db_url).await;
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_an_error() {
+        let db_url = init_test_db().await;
+        run_test_case("malformed_request.json", &[Id::new()], db_url).await;
+    }
+
+    #[tokio::test]
+    async fn joining_twice_is_refused() {
+        let db_url = init_test_db().await;
+        run_test_case("already_queued.json", &[Id::new()], db_url).await;
+    }
+
+    #[tokio::test]
+    async fn sessions_are_issued_and_renewed() {
+        let db_url = init_test_db().await;
+        let (mm_server, game_server) = start_servers(db_url, Duration::from_secs(60)).await;
+        let client = Client::new();
+        let session_url = url("http", mm_server.config.rest_address.clone(), "session");
+
+        // New users get a fresh id
+        let response = client
+            .post(session_url.clone())
+            .json(&SessionRequest { token: None })
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::OK, response.status());
+        let session = response
+            .json::<SessionResponse>()
+            .await
+            .expect("Failed to get session response body");
+        assert_eq!(
+            Ok(session.user_id),
+            auth::verify_token(TEST_TOKEN_SECRET.as_bytes(), &session.token)
+        );
+
+        // Renewing keeps the same user
+        let renewed = client
+            .post(session_url.clone())
+            .json(&SessionRequest {
+                token: Some(session.token),
+            })
+            .send()
+            .await
+            .expect("Request failed")
+            .json::<SessionResponse>()
+            .await
+            .expect("Failed to get session response body");
+        assert_eq!(session.user_id, renewed.user_id);
+
+        // Tokens we didn't sign are refused
+        let response = client
+            .post(session_url)
+            .json(&SessionRequest {
+                token: Some("garbage".to_string()),
+            })
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        mm_server.shutdown().await;
+        game_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn only_sessions_allow_cross_origin_requests() {
+        let db_url = init_test_db().await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let client = Client::new();
+        for (endpoint, allowed) in [
+            ("session", true),
+            ("game/result", false),
+            ("game/abandoned", false),
+            ("servers/register", false),
+        ] {
+            let response = client
+                .request(
+                    Method::OPTIONS,
+                    url("http", mm_server.config.rest_address.clone(), endpoint),
+                )
+                .header(ORIGIN, "http://localhost:3000")
+                .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .send()
+                .await
+                .expect("Request failed");
+            let allow_origin = response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN);
+            assert_eq!(allowed, allow_origin.is_some(), "{}", endpoint);
+        }
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn queued_users_are_sent_updates() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new()];
+        let interval = Duration::from_millis(100);
+        run_test_case_with_ping_interval("queue_updates.json", &ids, db_url, interval).await;
+    }
+
+    // Queue user1 behind user2 after user1's last game was abandoned by their opponent
+    async fn run_abandoned_priority(file_name: &str, abandoned_priority_window: Duration) {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new()];
+        let connection = Connection::open(&db_url).expect("Failed to open test database");
+        for (id, rating) in ids.iter().zip([1000.0, 2000.0]) {
+            connection
+                .execute(
+                    "INSERT INTO players (id, rating) VALUES (?1, ?2)",
+                    (id.to_string(), rating),
+                )
+                .expect("Failed to insert player");
+        }
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            abandoned_priority_window,
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let endpoint = url(
+            "http",
+            mm_server.config.rest_address.clone(),
+            "game/abandoned",
+        );
+        let response = auth::signed_json(
+            Client::new().post(endpoint),
+            TEST_TOKEN_SECRET.as_bytes(),
+            &PostGameAbandonedRequest {
+                game_id: Id::new(),
+                players: (ids[0], Id::new()),
+                joined: vec![ids[0]],
+            },
+        )
+        .send()
+        .await
+        .expect("Request failed");
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        run_scenario(file_name, &ids, &mm_server, "").await;
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn abandoned_players_rejoin_in_front() {
+        run_abandoned_priority("abandoned_priority.json", Duration::from_secs(60)).await;
+    }
+
+    #[tokio::test]
+    async fn abandoned_players_lose_priority_after_a_while() {
+        run_abandoned_priority("abandoned_priority_expired.json", Duration::ZERO).await;
+    }
+
+    #[tokio::test]
+    async fn players_are_requeued_when_game_creation_fails() {
+        let db_url = init_test_db().await;
+        // Nothing is listening at the game server url
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let ids = [Id::new(), Id::new()];
+        run_scenario("game_creation_fails.json", &ids, &mm_server, "").await;
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn refused_matches_are_not_recorded() {
+        let db_url = init_test_db().await;
+        let (mm_server, game_server) = start_servers(db_url.clone(), Duration::from_secs(60)).await;
+        let ids = [Id::new(), Id::new()];
+        // Seat user1 in a game matchmaking doesn't know about
+        let response = Client::new()
+            .post(url(
+                "http",
+                game_server.config.manager_address.clone(),
+                "create_game",
+            ))
+            .json(&CreateGameRequest {
+                game_id: Id::new(),
+                players: (ids[0], Id::new()),
+                games_to_win: 1,
+            })
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        let socket_address = game_server.config.socket_address.clone();
+        run_scenario("match_refused.json", &ids, &mm_server, &socket_address).await;
+
+        let connection = Connection::open(&db_url).expect("Failed to open test database");
+        let matches: u32 = connection
+            .query_row("SELECT COUNT(*) FROM match", [], |row| row.get(0))
+            .expect("Failed to count matches");
+        assert_eq!(0, matches);
+
+        mm_server.shutdown().await;
+        game_server.shutdown().await;
+    }
+
+    // Poll matchmaking's list of game servers until `done` accepts it
+    async fn wait_for_servers(
+        rest_address: &str,
+        done: impl Fn(&[GameServerInfo]) -> bool,
+    ) -> Vec<GameServerInfo> {
+        let client = Client::new();
+        for _ in 0..40 {
+            let servers = client
+                .get(url("http", rest_address, "servers"))
+                .send()
+                .await
+                .expect("Request failed")
+                .json::<Vec<GameServerInfo>>()
+                .await
+                .expect("Failed to get servers response body");
+            if done(&servers) {
+                return servers;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Game servers never reached the expected state");
+    }
+
+    #[tokio::test]
+    async fn game_servers_register_and_are_evicted() {
+        let db_url = init_test_db().await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![],
+            server_timeout: Duration::from_millis(300),
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let rest_address = mm_server.config.rest_address.clone();
+        let (gs_config, gs_listeners) = make_game_server_config().await;
+        let gs_config = GameServerConfig {
+            matchmaking_url: Some(url("http", rest_address.clone(), "")),
+            heartbeat_interval: Duration::from_millis(100),
+            max_concurrent_games: Some(10),
+            ..gs_config
+        };
+        let game_server = GameServer::new(gs_config.clone(), gs_listeners).await;
+
+        // The game server registers itself on startup, and keeps reporting its load
+        let servers = wait_for_servers(&rest_address, |servers| {
+            servers.len() == 1 && servers[0].live_games.is_some()
+        })
+        .await;
+        let server = &servers[0];
+        assert_eq!(url("http", &gs_config.manager_address, ""), server.url);
+        assert_eq!(
+            Some(gs_config.socket_address.clone()),
+            server.socket_address
+        );
+        assert_eq!(Some(10), server.capacity);
+        assert!(server.version.is_some());
+        assert_eq!(Some(0), server.live_games);
+
+        // Once it stops heartbeating it's evicted
+        game_server.shutdown().await;
+        wait_for_servers(&rest_address, |servers| servers.is_empty()).await;
+
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn games_on_evicted_servers_are_forgotten() {
+        let db_url = init_test_db().await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![],
+            server_timeout: Duration::from_millis(300),
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let rest_address = mm_server.config.rest_address.clone();
+        let (gs_config, gs_listeners) = make_game_server_config().await;
+        let gs_config = GameServerConfig {
+            matchmaking_url: Some(url("http", rest_address.clone(), "")),
+            heartbeat_interval: Duration::from_millis(100),
+            ..gs_config
+        };
+        let game_server = GameServer::new(gs_config, gs_listeners).await;
+        wait_for_servers(&rest_address, |servers| servers.len() == 1).await;
+
+        let ids = [Id::new(), Id::new()];
+        let socket_address = game_server.config.socket_address.clone();
+        run_scenario(
+            "queue_multiple_times.json",
+            &ids,
+            &mm_server,
+            &socket_address,
+        )
+        .await;
+
+        // The server goes down mid-game without reporting it, and is evicted
+        game_server.shutdown().await;
+        wait_for_servers(&rest_address, |servers| servers.is_empty()).await;
+        run_scenario("forgotten_game.json", &ids, &mm_server, "").await;
+
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn active_games_are_forgotten_after_a_while() {
+        let db_url = init_test_db().await;
+        let (gs_config, gs_listeners) = make_game_server_config().await;
+        let game_server = GameServer::new(gs_config, gs_listeners).await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![url("http", game_server.config.manager_address.clone(), "")],
+            active_game_timeout: Duration::ZERO,
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+
+        // The game is never reported, so it's forgotten once it's run too long
+        let ids = [Id::new(), Id::new()];
+        let socket_address = game_server.config.socket_address.clone();
+        run_scenario(
+            "queue_multiple_times.json",
+            &ids,
+            &mm_server,
+            &socket_address,
+        )
+        .await;
+        run_scenario("forgotten_game.json", &ids, &mm_server, "").await;
+
+        mm_server.shutdown().await;
+        game_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn server_requests_must_be_signed() {
+        let db_url = init_test_db().await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![],
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let rest_address = mm_server.config.rest_address.clone();
+        let server_url = url("http", "127.0.0.1:1", "");
+        let requests = [
+            (
+                "servers/register",
+                serde_json::json!(RegisterGameServerRequest {
+                    url: server_url.clone(),
+                    socket_address: "127.0.0.1:2".to_string(),
+                    capacity: None,
+                    version: "0.0.1".to_string(),
+                }),
+            ),
+            (
+                "servers/heartbeat",
+                serde_json::json!(GameServerHeartbeatRequest {
+                    url: server_url,
+                    live_games: 0,
+                }),
+            ),
+            (
+                "game/result",
+                serde_json::json!(PostGameResultsRequest {
+                    game_id: Id::new(),
+                    players: (Id::new(), Id::new()),
+                    games_to_win: 1,
+                    games_won: (1, 0),
+                }),
+            ),
+            (
+                "game/abandoned",
+                serde_json::json!(PostGameAbandonedRequest {
+                    game_id: Id::new(),
+                    players: (Id::new(), Id::new()),
+                    joined: vec![],
+                }),
+            ),
+        ];
+
+        let client = Client::new();
+        for (endpoint, body) in requests {
+            let endpoint_url = url("http", &rest_address, endpoint);
+            let unsigned = client.post(&endpoint_url).json(&body);
+            let forged =
+                auth::signed_json(client.post(&endpoint_url), b"not-the-test-secret", &body);
+            for request in [unsigned, forged] {
+                let response = request.send().await.expect("Request failed");
+                assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{}", endpoint);
+            }
+        }
+        wait_for_servers(&rest_address, |servers| servers.is_empty()).await;
+
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn games_go_to_least_loaded_server() {
+        let db_url = init_test_db().await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_rest_address = mm_config.rest_address.clone();
+        let mut game_servers = vec![];
+        for _ in 0..2 {
+            let (gs_config, gs_listeners) = make_game_server_config().await;
+            let gs_config = GameServerConfig {
+                matchmaking_url: Some(url("http", mm_rest_address.clone(), "")),
+                ..gs_config
+            };
+            game_servers.push(GameServer::new(gs_config, gs_listeners).await);
+        }
+        let (busy, idle) = (&game_servers[0], &game_servers[1]);
+
+        // Only the busy server is configured, the idle one has to register itself
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![url("http", busy.config.manager_address.clone(), "")],
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let client = Client::new();
+        let response = client
+            .post(url(
+                "http",
+                busy.config.manager_address.clone(),
+                "create_game",
+            ))
+            .json(&CreateGameRequest {
+                game_id: Id::new(),
+                players: (Id::new(), Id::new()),
+                games_to_win: 1,
+            })
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::CREATED, response.status());
+        wait_for_servers(&mm_rest_address, |servers| servers.len() == 2).await;
+
+        // The match is placed on the server without a game
+        let ids = [Id::new(), Id::new()];
+        let idle_address = idle.config.socket_address.clone();
+        run_scenario("queue_multiple_times.json", &ids, &mm_server, &idle_address).await;
+
+        mm_server.shutdown().await;
+        for game_server in game_servers.iter() {
+            game_server.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn full_servers_are_skipped() {
+        let db_url = init_test_db().await;
+        // Neither server registers, so matchmaking only learns the first is full by asking it
+        let mut game_servers = vec![];
+        for max_concurrent_games in [Some(0), None] {
+            let (gs_config, gs_listeners) = make_game_server_config().await;
+            let gs_config = GameServerConfig {
+                max_concurrent_games,
+                ..gs_config
+            };
+            game_servers.push(GameServer::new(gs_config, gs_listeners).await);
+        }
+        let open = &game_servers[1];
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: game_servers
+                .iter()
+                .map(|server| url("http", server.config.manager_address.clone(), ""))
+                .collect(),
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+
+        // Make the full server look like the least loaded one
+        let response = Client::new()
+            .post(url(
+                "http",
+                open.config.manager_address.clone(),
+                "create_game",
+            ))
+            .json(&CreateGameRequest {
+                game_id: Id::new(),
+                players: (Id::new(), Id::new()),
+                games_to_win: 1,
+            })
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        // The full server refuses the game, so it goes to the other one
+        let ids = [Id::new(), Id::new()];
+        let open_address = open.config.socket_address.clone();
+        run_scenario("queue_multiple_times.json", &ids, &mm_server, &open_address).await;
+
+        mm_server.shutdown().await;
+        for game_server in game_servers.iter() {
+            game_server.shutdown().await;
+        }
+    }
+
+    // Game server that reports no load, but fails to create every game it's asked for after
+    // `delay`
+    async fn start_failing_game_server(delay: Duration) -> String {
+        let (listener, address) = local_listener().await;
+        let app = axum::Router::new()
+            .route(
+                "/stats",
+                axum::routing::get(|| async {
+                    axum::Json(GameServerStatsResponse { live_games: 0 })
+                }),
+            )
+            .route(
+                "/create_game",
+                axum::routing::post(move || async move {
+                    tokio::time::sleep(delay).await;
+                    axum::http::StatusCode::INTERNAL_SERVER_ERROR
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        address
+    }
+
+    #[tokio::test]
+    async fn failing_servers_are_skipped() {
+        let db_url = init_test_db().await;
+        let failing_address = start_failing_game_server(Duration::ZERO).await;
+        let (gs_config, gs_listeners) = make_game_server_config().await;
+        let open = GameServer::new(gs_config, gs_listeners).await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![
+                url("http", failing_address, ""),
+                url("http", open.config.manager_address.clone(), ""),
+            ],
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+
+        // Make the failing server look like the least loaded one
+        let response = Client::new()
+            .post(url(
+                "http",
+                open.config.manager_address.clone(),
+                "create_game",
+            ))
+            .json(&CreateGameRequest {
+                game_id: Id::new(),
+                players: (Id::new(), Id::new()),
+                games_to_win: 1,
+            })
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        // The failing server can't create the game, so it goes to the other one
+        let ids = [Id::new(), Id::new()];
+        let open_address = open.config.socket_address.clone();
+        run_scenario("failing_server.json", &ids, &mm_server, &open_address).await;
+
+        mm_server.shutdown().await;
+        open.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn requests_are_handled_while_games_are_placed() {
+        let db_url = init_test_db().await;
+        let slow_address = start_failing_game_server(Duration::from_secs(2)).await;
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_config = MatchmakingConfig {
+            game_server_urls: vec![url("http", slow_address, "")],
+            ..mm_config
+        };
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+        let ids = [Id::new(), Id::new(), Id::new()];
+        run_scenario("slow_game_server.json", &ids, &mm_server, "").await;
+
+        // So is the REST api
+        let response = Client::new()
+            .post(url(
+                "http",
+                mm_server.config.rest_address.clone(),
+                "session",
+            ))
+            .json(&SessionRequest { token: None })
+            .timeout(Duration::from_millis(250))
+            .send()
+            .await
+            .expect("Request failed");
+        assert_eq!(StatusCode::OK, response.status());
+
+        mm_server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn played_games_update_ratings() {
+        let db_url = init_test_db().await;
+        let ids = [Id::new(), Id::new()];
+        let (mm_server, game_server) = start_servers(db_url.clone(), Duration::from_secs(60)).await;
+        let socket_address = game_server.config.socket_address.clone();
+        let captured = run_scenario(
+            "queue_multiple_times.json",
+            &ids,
+            &mm_server,
+            &socket_address,
+        )
+        .await;
+
+        // Play the game matchmaking created, with the tickets it handed out
+        let file_path = env!("CARGO_MANIFEST_DIR").to_string() + "/test/data/play_match.json";
+        let mut replacements = vec![
+            ("user1".to_string(), ids[0].to_string()),
+            ("user2".to_string(), ids[1].to_string()),
+        ];
+        replacements.extend(captured);
+        let game_socket_url = url("ws", socket_address, "");
         let address_lookup = HashMap::from([
             (
                 "user1".to_string(),
-                ServerAddress::WebSocket(url("ws", mm_server.config.socket_address.clone(), "")),
+                ServerAddress::WebSocket(game_socket_url.clone()),
             ),
             (
                 "user2".to_string(),
-                ServerAddress::WebSocket(url("ws", mm_server.config.socket_address.clone(), "")),
-            ),
-            (
-                "rest".to_string(),
-                ServerAddress::RestApi(url("http", mm_server.config.rest_address.clone(), "")),
+                ServerAddress::WebSocket(game_socket_url),
             ),
         ]);
+        TestCase::<GameClientRequest, GameClientResponse, DummyType, DummyType>::load(
+            file_path,
+            replacements,
+        )
+        .run(address_lookup)
+        .await;
+
+        // The game server reports the result against matchmaking's match row
+        let connection = Connection::open(&db_url).expect("Failed to open test database");
+        let mut ratings = None;
+        for _ in 0..40 {
+            ratings = connection
+                .query_row(
+                    "SELECT p1.rating, p2.rating FROM match
+                    JOIN match_results ON match_results.id = match.id
+                    JOIN players p1 ON p1.id = ?1
+                    JOIN players p2 ON p2.id = ?2
+                    WHERE match.player_1_id IN (?1, ?2)",
+                    (ids[0].to_string(), ids[1].to_string()),
+                    |row| Ok((row.get::<_, f64>(0)?, row.get::<_, f64>(1)?)),
+                )
+                .optional()
+                .expect("Failed to query ratings");
+            if ratings.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let (loser, winner) = ratings.expect("Result was never recorded");
+        assert_eq!(1016.0, winner);
+        assert_eq!(984.0, loser);
 
-        test_case.run(address_lookup).await;
         mm_server.shutdown().await;
         game_server.shutdown().await;
     }
+
+    #[tokio::test]
+    async fn game_result_updates_elo() {
+        let db_url = init_test_db().await;
+        let (player_1, player_2) = (Id::new(), Id::new());
+        let game_id = Id::new();
+        let connection = Connection::open(&db_url).expect("Failed to open test database");
+        connection
+            .execute(
+                "INSERT INTO match (id, player_1_id, player_2_id, games_to_win) VALUES (?1, ?2, ?3, ?4)",
+                (game_id.to_string(), player_1.to_string(), player_2.to_string(), 2),
+            )
+            .expect("Failed to insert match");
+
+        let (mm_config, mm_listeners) = make_config(db_url).await;
+        let mm_server = MatchmakingServer::new(mm_config, mm_listeners).await;
+
+        // Report players in the opposite order of the match row; player 2 won
+        let endpoint = url("http", mm_server.config.rest_address.clone(), "game/result");
+        let response = auth::signed_json(
+            Client::new().post(endpoint),
+            TEST_TOKEN_SECRET.as_bytes(),
+            &PostGameResultsRequest {
+                game_id,
+                players: (player_2, player_1),
+                games_to_win: 2,
+                games_won: (2, 1),
+            },
+        )
+        .send()
+        .await
+        .expect("Request failed");
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        let scores: (u8, u8) = connection
+            .query_row(
+                "SELECT player_1_score, player_2_score FROM match_results WHERE id = ?1",
+                [game_id.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("Match result not found");
+        assert_eq!((1, 2), scores);
+
+        let rating = |id: Id| -> f64 {
+            connection
+                .query_row(
+                    "SELECT rating FROM players WHERE id = ?1",
+                    [id.to_string()],
+                    |row| row.get(0),
+                )
+                .expect("Player rating not found")
+        };
+        let (rating_1, rating_2) = (rating(player_1), rating(player_2));
+        assert!(rating_1 < 1000.0);
+        assert!(rating_2 > 1000.0);
+        assert!((rating_1 + rating_2 - 2000.0).abs() < 1e-6);
+
+        mm_server.shutdown().await;
+    }
 }
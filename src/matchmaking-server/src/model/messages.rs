@@ -1,6 +1,4 @@
-use std::net::Ipv6Addr;
-
-use common::model::messages::Id;
+use common::{model::messages::Id, websocket::ClientError};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
 
@@ -9,6 +7,10 @@ use tokio::sync::mpsc::Sender;
 pub enum MatchmakingRequest {
     JoinQueue(Player),
     LeaveQueue(Id),
+    // Look up the player's active game, replying on their sender
+    GetServer(Player),
+    // Report the player's place in the queue, replying on their sender
+    Ping(Player),
 }
 
 // Message types for the matchmaking thread
@@ -25,6 +27,8 @@ pub struct Player {
 pub enum ClientRequest {
     // Add user to queue
     JoinQueue,
+    // Remove user from queue
+    LeaveQueue,
     // Ensure queue is still alive
     Ping,
     // User was disconnected from the match, and needs the server address again
@@ -36,11 +40,57 @@ pub enum ClientRequest {
 pub enum ClientResponse {
     // User actually joined queue
     JoinedQueue,
-    // Constant ping to let user know still connected
-    QueuePing { time_elapsed: u32 },
-    // Notify user to connect to server at given IP
-    MatchFound { game_id: Id, server_address: String },
-    // Notify user to connect to server at given IP
-    // TODO: implement querying for server when one goes down
-    JoinServer { server_ip: Ipv6Addr },
+    // User was removed from the queue at their request
+    LeftQueue,
+    // Queue status, sent when asked and periodically while queued. Times are in seconds, and the
+    // estimate is null until matchmaking has seen enough matches to make one.
+    QueuePing {
+        time_elapsed: u32,
+        position: u32,
+        estimated_wait: Option<u32>,
+    },
+    // User pinged the queue without being in it
+    NotInQueue,
+    // Queue status while no game server can take new games. Players stay queued.
+    ServersUnavailable,
+    // Notify user to connect to server at given IP. The ticket is sent with JoinGame, and can
+    // only be used once.
+    MatchFound {
+        game_id: Id,
+        server_address: String,
+        ticket: String,
+    },
+    // Notify user to reconnect to the game they're already in, with a fresh ticket
+    JoinServer {
+        game_id: Id,
+        server_address: String,
+        ticket: String,
+    },
+    // User asked for their game's server, but isn't in a game
+    NoActiveGame,
+    // Request couldn't be handled. The socket stays open unless the code is internal_error. Codes:
+    //   malformed_request  request wasn't valid JSON, or not a known request
+    //   non_text_message   request was sent as a binary message
+    //   already_in_queue   JoinQueue sent while already queued
+    //   in_game            JoinQueue sent while still in a game, rejoin it with GetServer
+    //   internal_error     server failed to handle the request, and closes the socket
+    Error {
+        code: String,
+        message: String,
+    },
+}
+
+impl ClientResponse {
+    pub fn error(code: &str, message: impl Into<String>) -> Self {
+        ClientResponse::Error {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ClientError> for ClientResponse {
+    fn from(error: ClientError) -> Self {
+        ClientResponse::error(error.code, error.message)
+    }
 }
@@ -0,0 +1,35 @@
+// Rating every player starts with before their first match
+pub const DEFAULT_RATING: f64 = 1000.0;
+// Maximum rating change from a single match
+pub const K_FACTOR: f64 = 32.0;
+
+// Probability that a player rated `rating` beats a player rated `opponent`
+pub fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+// Actual score for player 1: 1 for a win, 0 for a loss, 0.5 for a draw
+pub fn match_score(games_won: (u8, u8)) -> f64 {
+    match games_won.0.cmp(&games_won.1) {
+        std::cmp::Ordering::Greater => 1.0,
+        std::cmp::Ordering::Less => 0.0,
+        std::cmp::Ordering::Equal => 0.5,
+    }
+}
+
+// Compute both players' new ratings given player 1's actual score
+pub fn update_ratings(ratings: (f64, f64), player_1_score: f64) -> (f64, f64) {
+    let (rating_1, rating_2) = ratings;
+    let delta = K_FACTOR * (player_1_score - expected_score(rating_1, rating_2));
+    (rating_1 + delta, rating_2 - delta)
+}
+
+// Rating difference a player accepts right after joining the queue
+pub const INITIAL_RATING_WINDOW: f64 = 100.0;
+// How much the accepted rating difference grows per second spent in queue
+pub const RATING_WINDOW_GROWTH_PER_SECOND: f64 = 25.0;
+
+// Largest rating difference a player accepts after waiting `wait`
+pub fn rating_window(wait: std::time::Duration) -> f64 {
+    INITIAL_RATING_WINDOW + RATING_WINDOW_GROWTH_PER_SECOND * wait.as_secs_f64()
+}
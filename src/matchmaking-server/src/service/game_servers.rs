@@ -0,0 +1,454 @@
+use core::error;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use common::{
+    model::messages::{
+        CreateGameRequest, CreateGameResponse, GameServerInfo, GameServerStatsResponse,
+        RegisterGameServerRequest,
+    },
+    reqwest::{self, header::RETRY_AFTER, Client, Response, StatusCode, Url},
+    utility::{self, Backoff},
+};
+use tracing::{debug, error, info, warn};
+
+// Time allowed to connect to the game server, and for a whole request
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Retries for creating a single game before it counts as a failure. Kept short, since players
+// are waiting on the result.
+const CREATE_GAME_BACKOFF: Backoff = Backoff {
+    attempts: 3,
+    initial_delay: Duration::from_millis(100),
+};
+// How long to stop placing games on a full server that didn't say when to come back
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+// Failed calls in a row before we stop calling a game server
+const BREAKER_THRESHOLD: u32 = 3;
+// How long we stop calling a game server for before trying again
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+// Why a game couldn't be placed on any game server
+#[derive(Debug)]
+pub enum PlaceGameError {
+    // A server refused the game itself, e.g. because a player is still seated in another game.
+    // Placing the same players again right away won't help.
+    Rejected(reqwest::Error),
+    // No server could take the game right now
+    Unavailable(Box<dyn error::Error + Send + Sync>),
+}
+
+impl fmt::Display for PlaceGameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaceGameError::Rejected(e) => write!(f, "Game was rejected: {}", e),
+            PlaceGameError::Unavailable(e) => write!(f, "No game server available: {}", e),
+        }
+    }
+}
+
+impl error::Error for PlaceGameError {}
+
+// Why a game server didn't create a game
+enum CreateGameError {
+    // Server is at capacity, and asked us to come back later
+    Full { retry_after: Duration },
+    Request(reqwest::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    // Calls go through, counting failures in a row
+    Closed { failures: u32 },
+    // Calls are refused until the cooldown is over
+    Open { until: Instant },
+    // Cooldown is over, and the next call decides whether to close or open again
+    HalfOpen,
+}
+
+// Stops calling a game server that keeps failing, so it isn't hammered while it's down
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        Self {
+            state: BreakerState::Closed { failures: 0 },
+        }
+    }
+
+    // Whether a call may be made right now
+    pub fn allows(&mut self, now: Instant) -> bool {
+        match self.state {
+            BreakerState::Open { until } if now < until => false,
+            BreakerState::Open { until: _ } => {
+                info!("Cooldown over, letting a call through to test the game server");
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    // Whether calls are currently being refused
+    pub fn is_open(&self, now: Instant) -> bool {
+        matches!(self.state, BreakerState::Open { until } if now < until)
+    }
+
+    pub fn record_success(&mut self) {
+        if self.state == BreakerState::HalfOpen {
+            info!("Game server is back, closing its circuit breaker");
+        }
+        self.state = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        self.state = match self.state {
+            BreakerState::Closed { failures } if failures + 1 < BREAKER_THRESHOLD => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => {
+                error!(
+                    "Game server is failing, pausing calls to it for {:?}",
+                    BREAKER_COOLDOWN
+                );
+                BreakerState::Open {
+                    until: now + BREAKER_COOLDOWN,
+                }
+            }
+        };
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A game server matchmaking can place games on
+#[derive(Debug)]
+struct RegisteredServer {
+    url: Url,
+    breaker: CircuitBreaker,
+    // What the server sent when registering. None for servers from config that haven't registered.
+    socket_address: Option<String>,
+    capacity: Option<u32>,
+    version: Option<String>,
+    // Last load the server reported or we asked for
+    live_games: Option<u32>,
+    // Server refused a game for being full, and asked us not to place games on it until then
+    full_until: Option<Instant>,
+    // Last time the server registered or sent a heartbeat. Servers from config that never
+    // registered don't heartbeat, and are never evicted.
+    last_seen: Option<Instant>,
+}
+
+impl RegisteredServer {
+    fn new(url: Url) -> Self {
+        Self {
+            url,
+            breaker: CircuitBreaker::new(),
+            socket_address: None,
+            capacity: None,
+            version: None,
+            live_games: None,
+            full_until: None,
+            last_seen: None,
+        }
+    }
+}
+
+// Game servers known to matchmaking, filled from config and by servers registering themselves.
+// Games are placed on the healthy server with the fewest live games.
+#[derive(Debug)]
+pub struct GameServerRegistry {
+    client: Client,
+    servers: Vec<RegisteredServer>,
+}
+
+impl GameServerRegistry {
+    pub fn new(urls: Vec<Url>) -> Self {
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build game server client");
+        let mut registry = Self {
+            client,
+            servers: vec![],
+        };
+        for url in urls {
+            registry.servers.push(RegisteredServer::new(url));
+        }
+        registry
+    }
+
+    // Add a server to the pool, or update it if it registers again. Returns false if it was
+    // already known.
+    pub fn register(&mut self, url: Url, request: RegisterGameServerRequest, now: Instant) -> bool {
+        let known = self.servers.iter().position(|server| server.url == url);
+        let server = match known {
+            Some(index) => &mut self.servers[index],
+            None => {
+                self.servers.push(RegisteredServer::new(url));
+                self.servers.last_mut().unwrap()
+            }
+        };
+        info!(
+            "Registered game server {} version {}, capacity {:?}",
+            server.url, request.version, request.capacity
+        );
+        server.socket_address = Some(request.socket_address);
+        server.capacity = request.capacity;
+        server.version = Some(request.version);
+        server.last_seen = Some(now);
+        known.is_none()
+    }
+
+    // Record a server's load. Returns false if the server isn't registered.
+    pub fn heartbeat(&mut self, url: &Url, live_games: u32, now: Instant) -> bool {
+        let Some(server) = self.servers.iter_mut().find(|server| server.url == *url) else {
+            return false;
+        };
+        server.live_games = Some(live_games);
+        server.last_seen = Some(now);
+        true
+    }
+
+    // Drop servers that haven't sent a heartbeat within `timeout`, returning their urls
+    pub fn evict_stale(&mut self, now: Instant, timeout: Duration) -> Vec<Url> {
+        let mut evicted = vec![];
+        self.servers.retain(|server| match server.last_seen {
+            Some(last_seen) if now - last_seen > timeout => {
+                warn!(
+                    "Evicting game server {}, no heartbeat since {:?}",
+                    server.url,
+                    now - last_seen
+                );
+                evicted.push(server.url.clone());
+                false
+            }
+            _ => true,
+        });
+        evicted
+    }
+
+    pub fn servers(&self) -> Vec<GameServerInfo> {
+        self.servers
+            .iter()
+            .map(|server| GameServerInfo {
+                url: server.url.to_string(),
+                socket_address: server.socket_address.clone(),
+                capacity: server.capacity,
+                version: server.version.clone(),
+                live_games: server.live_games,
+            })
+            .collect()
+    }
+
+    // Whether every server's circuit breaker is refusing calls, or there are no servers at all
+    pub fn is_unavailable(&self, now: Instant) -> bool {
+        self.servers
+            .iter()
+            .all(|server| server.breaker.is_open(now))
+    }
+
+    // Pick the servers a game may be placed on right now. The calls to them are made through the
+    // returned placement, which doesn't borrow the registry, and what they found out is recorded
+    // afterwards with `finish_placement`.
+    pub fn start_placement(&mut self, now: Instant) -> Placement {
+        let mut servers = vec![];
+        for server in self.servers.iter_mut() {
+            if server.full_until.is_some_and(|until| now < until) {
+                continue;
+            }
+            if !server.breaker.allows(now) {
+                continue;
+            }
+            servers.push((server.url.clone(), server.capacity));
+        }
+        Placement {
+            client: self.client.clone(),
+            servers,
+            events: vec![],
+        }
+    }
+
+    // Record what placing a game found out about each server
+    pub fn finish_placement(&mut self, placement: Placement) {
+        for (url, event) in placement.events {
+            // The server may have been evicted while the game was being placed
+            let Some(server) = self.servers.iter_mut().find(|server| server.url == url) else {
+                continue;
+            };
+            match event {
+                ServerEvent::Load { live_games } => server.live_games = Some(live_games),
+                ServerEvent::Full { until } => server.full_until = Some(until),
+                ServerEvent::Failed { at } => server.breaker.record_failure(at),
+                ServerEvent::Succeeded => server.breaker.record_success(),
+            }
+        }
+    }
+
+    async fn live_games(client: &Client, url: &Url) -> Result<u32> {
+        let stats = client
+            .get(url.join("stats")?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<GameServerStatsResponse>()
+            .await?;
+        Ok(stats.live_games)
+    }
+
+    // POST to the game server to create a game thread, retrying with exponential backoff. A full
+    // server isn't retried.
+    async fn post_create_game(
+        client: &Client,
+        url: &Url,
+        request: &CreateGameRequest,
+    ) -> std::result::Result<CreateGameResponse, CreateGameError> {
+        let url = url
+            .join("create_game")
+            .expect("create_game is a valid path");
+        let url = &url;
+        let create_game = |attempt| async move {
+            let response = client.post(url.clone()).json(request).send().await;
+            if let Ok(response) = &response {
+                if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                    return Err(CreateGameError::Full {
+                        retry_after: Self::retry_after(response),
+                    });
+                }
+            }
+            let response = response.and_then(|response| response.error_for_status());
+            let error = match response {
+                Ok(response) => match response.json::<CreateGameResponse>().await {
+                    Ok(response) => return Ok(response),
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            warn!(
+                "Failed to create game at {} (attempt {}/{}): {}",
+                url, attempt, CREATE_GAME_BACKOFF.attempts, error
+            );
+            Err(CreateGameError::Request(error))
+        };
+        // A full server said when to come back, and a refused request won't fare better next time
+        utility::retry(CREATE_GAME_BACKOFF, create_game, |error| match error {
+            CreateGameError::Full { .. } => false,
+            CreateGameError::Request(e) => {
+                !e.status().is_some_and(|status| status.is_client_error())
+            }
+        })
+        .await
+    }
+
+    // How long a full server asked us to wait, in seconds
+    fn retry_after(response: &Response) -> Duration {
+        response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RETRY_AFTER)
+    }
+}
+
+// Something placing a game found out about a server
+#[derive(Debug)]
+enum ServerEvent {
+    Load { live_games: u32 },
+    Full { until: Instant },
+    Failed { at: Instant },
+    Succeeded,
+}
+
+// Servers picked for placing a game, along with what was found out about them while placing it
+#[derive(Debug)]
+pub struct Placement {
+    client: Client,
+    // Url and capacity of each server
+    servers: Vec<(Url, Option<u32>)>,
+    events: Vec<(Url, ServerEvent)>,
+}
+
+impl Placement {
+    // Create a game on the least-loaded server that has room, returning the server's url along
+    // with its response
+    pub async fn create_game(
+        &mut self,
+        request: &CreateGameRequest,
+    ) -> std::result::Result<(Url, CreateGameResponse), PlaceGameError> {
+        let mut candidates: Vec<(Url, u32)> = vec![];
+        for (url, capacity) in self.servers.clone() {
+            match GameServerRegistry::live_games(&self.client, &url).await {
+                Ok(live_games) => {
+                    self.events
+                        .push((url.clone(), ServerEvent::Load { live_games }));
+                    if capacity.is_some_and(|capacity| live_games >= capacity) {
+                        debug!("Game server {} is full", url);
+                        continue;
+                    }
+                    candidates.push((url, live_games));
+                }
+                Err(e) => {
+                    warn!("Failed to get load of game server {}: {}", url, e);
+                    let at = Instant::now();
+                    self.events.push((url, ServerEvent::Failed { at }));
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return Err(PlaceGameError::Unavailable(
+                "no healthy server has room".into(),
+            ));
+        }
+
+        // Least loaded first, moving on to the next if a server turns out to be full or fails
+        candidates.sort_by_key(|(_, live_games)| *live_games);
+        for (url, live_games) in candidates {
+            debug!("Placing game on {} with {} live games", url, live_games);
+            match GameServerRegistry::post_create_game(&self.client, &url, request).await {
+                Ok(response) => {
+                    self.events.push((url.clone(), ServerEvent::Succeeded));
+                    return Ok((url, response));
+                }
+                Err(CreateGameError::Full { retry_after }) => {
+                    info!(
+                        "Game server {} is full, not placing games on it for {:?}",
+                        url, retry_after
+                    );
+                    let until = Instant::now() + retry_after;
+                    self.events.push((url, ServerEvent::Full { until }));
+                }
+                Err(CreateGameError::Request(e)) => {
+                    // The request itself was refused, which says nothing about the server's health
+                    if e.status().is_some_and(|status| status.is_client_error()) {
+                        return Err(PlaceGameError::Rejected(e));
+                    }
+                    warn!(
+                        "Game server {} failed to create a game, trying the next one: {}",
+                        url, e
+                    );
+                    let at = Instant::now();
+                    self.events.push((url, ServerEvent::Failed { at }));
+                }
+            }
+        }
+        Err(PlaceGameError::Unavailable(
+            "every server is full or failing".into(),
+        ))
+    }
+}
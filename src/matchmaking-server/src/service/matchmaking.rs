@@ -1,26 +1,40 @@
 use core::error;
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::State,
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use common::{
-    model::messages::{CreateGameRequest, CreateGameResponse, Id, PostGameResultsRequest},
-    reqwest::{Client, Url},
+    auth::{self, JOIN_TICKET_TTL, SESSION_TOKEN_TTL, SIGNATURE_HEADER},
+    model::messages::{
+        CreateGameRequest, CreateGameResponse, GameServerHeartbeatRequest, Id,
+        PostGameAbandonedRequest, PostGameResultsRequest, RegisterGameServerRequest,
+        SessionRequest, SessionResponse,
+    },
+    reqwest::Url,
 };
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
 use tokio::{
-    sync::{broadcast, mpsc::Receiver, Mutex},
+    net::TcpListener,
+    sync::{
+        broadcast,
+        mpsc::{Receiver, Sender},
+        Mutex,
+    },
     task::JoinHandle,
 };
-use tower_http::trace::TraceLayer;
+use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::{debug, error, info, warn};
 
 use crate::{
@@ -28,6 +42,15 @@ use crate::{
     model::messages::{ClientResponse, MatchmakingRequest, Player},
 };
 
+use super::{
+    elo,
+    game_servers::{GameServerRegistry, PlaceGameError, Placement},
+    queue::{PlayerQueue, QueuedPlayer, Throughput},
+};
+
+// How long players are kept from being paired after a game server refused a game with them
+const REFUSED_MATCH_HOLD: Duration = Duration::from_secs(10);
+
 pub struct Game {
     pub id: Id,
     pub player1: Player,
@@ -35,22 +58,126 @@ pub struct Game {
     pub server_address: Url,
 }
 
+// Game a player was matched into, kept so they can find it again after disconnecting
+#[derive(Debug, Clone)]
+struct ActiveGame {
+    pub game_id: Id,
+    pub server_address: String,
+    // Game server the game was placed on
+    pub server_url: Url,
+    // Forgotten after this, in case the game server never reports the game as over
+    pub expires_at: Instant,
+}
+
 struct MatchmakingServiceState {
     pub config: MatchmakingConfig,
-    pub queue: VecDeque<Player>,
-    pub users_in_queue: HashSet<Id>,
+    pub queue: PlayerQueue,
+    // Players whose game was abandoned by their opponent, and when. They're placed at the front
+    // if they rejoin within the config's abandoned_priority_window.
+    pub priority_players: HashMap<Id, Instant>,
+    pub active_games: HashMap<Id, ActiveGame>,
+    // Players taken out of the queue while a game is placed for them. They're put back if it
+    // can't be, unless they leave in the meantime.
+    pub placing: HashSet<Id>,
+    pub throughput: Throughput,
+    pub game_servers: GameServerRegistry,
 }
 
 impl MatchmakingServiceState {
-    pub fn add_user(&mut self, player: Player) {
-        let user_id = player.clone().id;
-        if self.users_in_queue.contains(&user_id) {
+    // Queue the player, returning false if they already were
+    pub fn add_user(&mut self, player: Player, rating: f64) -> bool {
+        let user_id = player.id;
+        if self.queue.contains(&user_id) || self.placing.contains(&user_id) {
             warn!("User {:?} was already in the queue", user_id);
-            return;
+            return false;
+        }
+        info!("Adding user {:?} to queue with rating {}", user_id, rating);
+        let window = self.config.abandoned_priority_window;
+        let abandoned_at = self.priority_players.remove(&user_id);
+        if abandoned_at.is_some_and(|at| at.elapsed() <= window) {
+            info!("User {:?} rejoined with priority", user_id);
+            self.queue.push_front(player, rating);
+        } else {
+            self.queue.push_back(player, rating);
+        }
+        true
+    }
+
+    // Single-use ticket letting the player take their seat in the game
+    fn join_ticket(&self, game_id: Id, player_id: Id) -> String {
+        auth::issue_join_ticket(
+            self.config.token_secret.as_bytes(),
+            game_id,
+            player_id,
+            JOIN_TICKET_TTL,
+        )
+    }
+
+    // Current queue status of every queued player, with the sender to send it on
+    fn queue_pings(&self) -> Vec<(Sender<ClientResponse>, ClientResponse)> {
+        let now = Instant::now();
+        self.queue
+            .in_order()
+            .into_iter()
+            .enumerate()
+            .map(|(index, player)| {
+                let message = self.queue_ping(player, index + 1, now);
+                (player.player.sender.clone(), message)
+            })
+            .collect()
+    }
+
+    // Queue status for a player at the given position
+    fn queue_ping(&self, player: &QueuedPlayer, position: usize, now: Instant) -> ClientResponse {
+        if self.game_servers.is_unavailable(now) {
+            return ClientResponse::ServersUnavailable;
+        }
+        ClientResponse::QueuePing {
+            time_elapsed: (now - player.joined_at).as_secs() as u32,
+            position: position as u32,
+            estimated_wait: self
+                .throughput
+                .estimated_wait(position, now)
+                .map(|wait| wait.as_secs() as u32),
+        }
+    }
+}
+
+// JSON body sent by a game server, signed with the token secret. Requests without a valid
+// signature are refused with a 401.
+struct SignedJson<T>(T);
+
+#[async_trait]
+impl<T> FromRequest<Arc<Mutex<MatchmakingServiceState>>> for SignedJson<T>
+where
+    T: DeserializeOwned,
+{
+    type Rejection = Response;
+
+    async fn from_request(
+        request: Request,
+        state: &Arc<Mutex<MatchmakingServiceState>>,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let signature = request
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Some(signature) = signature else {
+            let message = format!("Missing {} header", SIGNATURE_HEADER);
+            return Err((StatusCode::UNAUTHORIZED, message).into_response());
+        };
+        let secret = state.lock().await.config.token_secret.clone();
+        if let Err(e) = auth::verify_body(secret.as_bytes(), &body, &signature) {
+            warn!("Refused request with a bad signature: {}", e);
+            let message = format!("Invalid signature: {}", e);
+            return Err((StatusCode::UNAUTHORIZED, message).into_response());
         }
-        info!("Adding user {:?} to queue", user_id);
-        self.queue.push_back(player);
-        self.users_in_queue.insert(user_id);
+        let Json(value) = Json::<T>::from_bytes(&body).map_err(IntoResponse::into_response)?;
+        Ok(SignedJson(value))
     }
 }
 
@@ -61,36 +188,184 @@ type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 // TODO: this is a controller. Separate threads into their own "services"? 🤔
 impl MatchmakingService {
     // TODO: How can we reduce the size of this state?
-    async fn read_queue(state: Arc<Mutex<MatchmakingServiceState>>) -> Result<()> {
-        let mut state = state.lock().await;
-        let mut unmatched_players: VecDeque<Player> = VecDeque::new();
-        let mut matches: Vec<(Player, Player)> = vec![];
-        while let Some(player) = state.queue.pop_front() {
-            if let Some(enemy) = unmatched_players.pop_front() {
-                info!("Matched {:?} and {:?}", player.id, enemy.id);
-                matches.push((player, enemy));
-                continue;
+    // Pair up queued players and place their games. The state is only locked around pairing and
+    // handling each outcome, never while calling game servers.
+    async fn read_queue(state: Arc<Mutex<MatchmakingServiceState>>) {
+        let (matches, db_url) = {
+            let mut state = state.lock().await;
+            let state = &mut *state;
+            let now = Instant::now();
+            let evicted = state
+                .game_servers
+                .evict_stale(now, state.config.server_timeout);
+            // Players can't rejoin games on servers that went away, or that have run too long
+            state
+                .active_games
+                .retain(|_, game| now < game.expires_at && !evicted.contains(&game.server_url));
+            // Leave everyone queued until the game server is back
+            if state.game_servers.is_unavailable(Instant::now()) {
+                return;
+            }
+            let matches = Self::pair_players(&mut state.queue, Instant::now());
+            for (queued1, queued2) in matches.iter() {
+                state.placing.extend([queued1.id(), queued2.id()]);
             }
-            unmatched_players.push_back(player);
+            (matches, state.config.db_url.clone())
+        };
+        if matches.is_empty() {
+            return;
         }
-        for (player1, player2) in matches.iter() {
-            state.users_in_queue.remove(&player1.id);
-            state.users_in_queue.remove(&player2.id);
+
+        // Players who go back in front of the queue, in the order they were matched
+        let mut requeued: Vec<QueuedPlayer> = vec![];
+        let mut dropped: Vec<Id> = vec![];
+        for (mut queued1, mut queued2) in matches {
+            // Drop anyone who disconnected since joining, and put their opponent back
+            if queued1.player.sender.is_closed() || queued2.player.sender.is_closed() {
+                for queued in [queued1, queued2] {
+                    if queued.player.sender.is_closed() {
+                        warn!("Dropping {:?} from queue, connection closed", queued.id());
+                        dropped.push(queued.id());
+                    } else {
+                        requeued.push(queued);
+                    }
+                }
+                continue;
+            }
+            let (player1, player2) = (&queued1.player, &queued2.player);
 
             // Create game
-            let response = Self::create_game(&state.config, (player1.id, player2.id)).await?;
+            let players = (player1.id, player2.id);
+            let mut placement = state
+                .lock()
+                .await
+                .game_servers
+                .start_placement(Instant::now());
+            let result = Self::create_game(&db_url, &mut placement, players).await;
+            let mut state = state.lock().await;
+            state.game_servers.finish_placement(placement);
+            let (server_url, response) = match result {
+                Ok(placed) => placed,
+                Err(e) => {
+                    error!(
+                        "Failed to create game for {:?} and {:?}: {}",
+                        player1.id, player2.id, e
+                    );
+                    // Don't ask again every tick, the server would only refuse them again
+                    if matches!(e, PlaceGameError::Rejected(_)) {
+                        let held_until = Some(Instant::now() + REFUSED_MATCH_HOLD);
+                        queued1.held_until = held_until;
+                        queued2.held_until = held_until;
+                    }
+                    requeued.extend([queued1, queued2]);
+                    continue;
+                }
+            };
+            for player in [player1, player2] {
+                state.placing.remove(&player.id);
+            }
 
-            // Notify players
-            let message = ClientResponse::MatchFound {
+            // Remember the game in case players need to find it again
+            let active_game = ActiveGame {
                 game_id: response.game_id,
-                server_address: response.address,
+                server_address: response.address.clone(),
+                server_url,
+                expires_at: Instant::now() + state.config.active_game_timeout,
             };
-            player1.sender.send(message.clone()).await?;
-            player2.sender.send(message.clone()).await?;
+            state.active_games.insert(player1.id, active_game.clone());
+            state.active_games.insert(player2.id, active_game);
+
+            // Notify players, each with their own ticket for their seat
+            for player in [player1, player2] {
+                let message = ClientResponse::MatchFound {
+                    game_id: response.game_id,
+                    server_address: response.address.clone(),
+                    ticket: state.join_ticket(response.game_id, player.id),
+                };
+                if let Err(e) = player.sender.try_send(message) {
+                    // The game will be cancelled if they never join
+                    warn!("Failed to notify {:?} of their match: {}", player.id, e);
+                }
+            }
+            state.throughput.record_match(Instant::now());
         }
-        state.queue = unmatched_players;
 
-        Ok(())
+        let unavailable = {
+            let mut state = state.lock().await;
+            for id in dropped {
+                state.placing.remove(&id);
+            }
+            for queued in requeued.into_iter().rev() {
+                // Players who left while their game was being placed stay out
+                if state.placing.remove(&queued.id()) {
+                    state.queue.requeue_front(queued);
+                }
+            }
+            state.game_servers.is_unavailable(Instant::now())
+        };
+
+        // Let everyone know right away if the game server just went down
+        if unavailable {
+            Self::ping_queue(&state).await;
+        }
+    }
+
+    // Send every queued player their current queue status. Players who've stopped reading miss
+    // it rather than holding up the queue.
+    async fn ping_queue(state: &Arc<Mutex<MatchmakingServiceState>>) {
+        let pings = state.lock().await.queue_pings();
+        for (sender, message) in pings {
+            Self::reply(&sender, message);
+        }
+    }
+
+    // Send a response without waiting for room in the player's channel
+    fn reply(sender: &Sender<ClientResponse>, response: ClientResponse) {
+        if let Err(err) = sender.try_send(response) {
+            warn!("Failed to send MatchmakingResponse: {}", err);
+        }
+    }
+
+    // Pair players in queue order, each with the closest-rated player that both sides accept.
+    // Matched players are removed from the queue.
+    fn pair_players(queue: &mut PlayerQueue, now: Instant) -> Vec<(QueuedPlayer, QueuedPlayer)> {
+        let mut pairs: Vec<(Id, Id)> = vec![];
+        let mut matched: HashSet<Id> = HashSet::new();
+        let waiting: Vec<&QueuedPlayer> = queue
+            .in_order()
+            .into_iter()
+            .filter(|player| !player.is_held(now))
+            .collect();
+        for (position, player) in waiting.iter().enumerate() {
+            if matched.contains(&player.id()) {
+                continue;
+            }
+            let opponent = waiting[position + 1..]
+                .iter()
+                .filter(|other| !matched.contains(&other.id()) && player.accepts(other, now))
+                .min_by(|a, b| {
+                    let a = (player.rating - a.rating).abs();
+                    let b = (player.rating - b.rating).abs();
+                    a.total_cmp(&b)
+                });
+            if let Some(enemy) = opponent {
+                info!(
+                    "Matched {:?} ({}) and {:?} ({})",
+                    player.id(),
+                    player.rating,
+                    enemy.id(),
+                    enemy.rating
+                );
+                matched.insert(player.id());
+                matched.insert(enemy.id());
+                pairs.push((player.id(), enemy.id()));
+            }
+        }
+
+        pairs
+            .into_iter()
+            .filter_map(|(player, enemy)| Some((queue.remove(&player)?, queue.remove(&enemy)?)))
+            .collect()
     }
 
     pub fn new() -> Self {
@@ -100,17 +375,24 @@ impl MatchmakingService {
     pub async fn run(
         &self,
         config: MatchmakingConfig,
+        listener: TcpListener,
         shutdown_receiver: &mut broadcast::Receiver<()>,
         ws_receiver: Arc<Mutex<Receiver<MatchmakingRequest>>>,
     ) {
-        let rest_address = config.rest_address.clone(); // Copy rest_address address before moving config into
-        let game_server_url =
-            Url::parse(&config.game_server_url).expect("Failed to parse game server url");
+        let game_server_urls = config
+            .game_server_urls
+            .iter()
+            .map(|url| Url::parse(url).expect("Failed to parse game server url"))
+            .collect();
         // state
         let state = Arc::new(Mutex::new(MatchmakingServiceState {
             config: config.clone(),
-            queue: VecDeque::new(),
-            users_in_queue: HashSet::new(),
+            queue: PlayerQueue::new(),
+            priority_players: HashMap::new(),
+            active_games: HashMap::new(),
+            placing: HashSet::new(),
+            throughput: Throughput::new(Instant::now()),
+            game_servers: GameServerRegistry::new(game_server_urls),
         }));
 
         // Thread to poll and push messages back to the websocket service
@@ -121,16 +403,28 @@ impl MatchmakingService {
                 .await
         });
 
+        // Thread pairing queued players and placing their games
+        let queue_shutdown_receiver = shutdown_receiver.resubscribe();
+        let queue_state = state.clone();
+        let queue_handle =
+            tokio::spawn(
+                async move { Self::queue_thread(queue_state, queue_shutdown_receiver).await },
+            );
+
         // REST thread
         let rest_shutdown_receiver = shutdown_receiver.resubscribe();
         let rest_handle: JoinHandle<()> = tokio::spawn(async move {
-            Self::rest_endpoint_thread(&rest_address, rest_shutdown_receiver, state).await
+            Self::rest_endpoint_thread(listener, rest_shutdown_receiver, state).await
         });
 
         forward_socket_handle
             .await
             .expect("Socket listener exited non-gracefully");
 
+        queue_handle
+            .await
+            .expect("Queue thread exited non-gracefully");
+
         rest_handle
             .await
             .expect("REST endpoint exited non-gracefully");
@@ -142,7 +436,8 @@ impl MatchmakingService {
         ws_receiver: Arc<Mutex<Receiver<MatchmakingRequest>>>,
     ) {
         let mut receiver = ws_receiver.lock().await;
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(50));
+        let mut ping_interval =
+            tokio::time::interval(state.lock().await.config.queue_ping_interval);
 
         info!("Initialized matchmaking service");
         loop {
@@ -154,16 +449,41 @@ impl MatchmakingService {
                 message = receiver.recv() => {
                     Self::handle_message(state.clone(), message).await;
                 }
-                _ = interval.tick() => {
-                    Self::read_queue(state.clone()).await.expect("Failed to read internal queue");
+                _ = ping_interval.tick() => {
+                    Self::ping_queue(&state).await;
                 }
             }
         }
         info!("Exiting matchmaking service");
     }
 
+    // Runs separately from the socket thread, so requests are still handled while games are placed
+    async fn queue_thread(
+        state: Arc<Mutex<MatchmakingServiceState>>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(50));
+        loop {
+            tokio::select! {
+                _ = shutdown_receiver.recv() => {
+                    break
+                }
+                _ = interval.tick() => {
+                    // Don't wait out a slow game server when shutting down
+                    tokio::select! {
+                        _ = shutdown_receiver.recv() => {
+                            break
+                        }
+                        _ = Self::read_queue(state.clone()) => {}
+                    }
+                }
+            }
+        }
+        info!("Exiting queue thread");
+    }
+
     async fn rest_endpoint_thread(
-        address: &String,
+        listener: TcpListener,
         mut shutdown_receiver: broadcast::Receiver<()>,
         state: Arc<Mutex<MatchmakingServiceState>>,
     ) {
@@ -171,11 +491,18 @@ impl MatchmakingService {
             .layer(TraceLayer::new_for_http())
             .route("/", get(Self::root))
             .route("/game/result", post(Self::post_game_result))
+            .route("/game/abandoned", post(Self::post_game_abandoned))
+            .route("/servers", get(Self::get_servers))
+            .route("/servers/register", post(Self::post_register_server))
+            .route("/servers/heartbeat", post(Self::post_server_heartbeat))
+            // Browsers call /session from the web client's origin. Other endpoints are for servers,
+            // so browsers are kept from calling them.
+            .route(
+                "/session",
+                post(Self::post_session).layer(CorsLayer::permissive()),
+            )
             .with_state(state);
-        let listener = tokio::net::TcpListener::bind(address.clone())
-            .await
-            .unwrap();
-        info!("Game manager listening on {}", address);
+        info!("Game manager listening on {:?}", listener.local_addr());
         axum::serve(listener, app)
             .with_graceful_shutdown(async move {
                 shutdown_receiver
@@ -193,44 +520,222 @@ impl MatchmakingService {
 
     async fn post_game_result(
         State(state): State<Arc<Mutex<MatchmakingServiceState>>>,
-        Json(request): Json<PostGameResultsRequest>,
+        SignedJson(request): SignedJson<PostGameResultsRequest>,
     ) -> Response {
-        let db_path = state.lock().await.config.db_url.clone();
+        let db_path = {
+            let mut state = state.lock().await;
+            for player_id in [request.players.0, request.players.1] {
+                state.active_games.remove(&player_id);
+            }
+            state.config.db_url.clone()
+        };
         match Self::write_game_result_and_update_elo(db_path, request).await {
             Ok(_) => StatusCode::CREATED.into_response(),
             Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
         }
     }
 
+    async fn post_game_abandoned(
+        State(state): State<Arc<Mutex<MatchmakingServiceState>>>,
+        SignedJson(request): SignedJson<PostGameAbandonedRequest>,
+    ) -> Response {
+        let mut state = state.lock().await;
+        info!(
+            "Game {} was abandoned, players {:?} joined",
+            request.game_id, request.joined
+        );
+        if let Err(e) = Self::write_game_abandoned(&state.config.db_url, &request) {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+        for player_id in [request.players.0, request.players.1] {
+            state.active_games.remove(&player_id);
+        }
+        // Nothing requeues them, rejoining is up to the client. Forget anyone who never did.
+        let now = Instant::now();
+        let window = state.config.abandoned_priority_window;
+        state
+            .priority_players
+            .retain(|_, abandoned_at| now - *abandoned_at <= window);
+        state
+            .priority_players
+            .extend(request.joined.into_iter().map(|id| (id, now)));
+        StatusCode::CREATED.into_response()
+    }
+
+    async fn post_register_server(
+        State(state): State<Arc<Mutex<MatchmakingServiceState>>>,
+        SignedJson(request): SignedJson<RegisterGameServerRequest>,
+    ) -> Response {
+        let url = match Url::parse(&request.url) {
+            Ok(url) => url,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        };
+        let mut state = state.lock().await;
+        if state.game_servers.register(url, request, Instant::now()) {
+            StatusCode::CREATED.into_response()
+        } else {
+            StatusCode::OK.into_response()
+        }
+    }
+
+    async fn post_server_heartbeat(
+        State(state): State<Arc<Mutex<MatchmakingServiceState>>>,
+        SignedJson(request): SignedJson<GameServerHeartbeatRequest>,
+    ) -> Response {
+        let url = match Url::parse(&request.url) {
+            Ok(url) => url,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        };
+        let mut state = state.lock().await;
+        if state
+            .game_servers
+            .heartbeat(&url, request.live_games, Instant::now())
+        {
+            StatusCode::OK.into_response()
+        } else {
+            // Evicted or never registered, the server should register again
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+
+    // Issue a session token for the client's sockets
+    async fn post_session(
+        State(state): State<Arc<Mutex<MatchmakingServiceState>>>,
+        Json(request): Json<SessionRequest>,
+    ) -> Response {
+        let secret = state.lock().await.config.token_secret.clone();
+        let user_id = match request.token {
+            Some(token) => match auth::verify_token(secret.as_bytes(), &token) {
+                Ok(user_id) => user_id,
+                Err(e) => return (StatusCode::UNAUTHORIZED, e.to_string()).into_response(),
+            },
+            None => Id::new(),
+        };
+        let token = auth::issue_token(secret.as_bytes(), user_id, SESSION_TOKEN_TTL);
+        (StatusCode::OK, Json(SessionResponse { user_id, token })).into_response()
+    }
+
+    async fn get_servers(State(state): State<Arc<Mutex<MatchmakingServiceState>>>) -> Response {
+        let state = state.lock().await;
+        (StatusCode::OK, Json(state.game_servers.servers())).into_response()
+    }
+
+    fn write_game_abandoned(db_path: &str, request: &PostGameAbandonedRequest) -> Result<()> {
+        let connection = Connection::open(db_path)?;
+        connection.execute(
+            "INSERT INTO match_results (id, abandoned) VALUES (?1, TRUE)",
+            [request.game_id.to_string()],
+        )?;
+        Ok(())
+    }
+
     async fn write_game_result_and_update_elo(
         db_path: String,
         request: PostGameResultsRequest,
     ) -> Result<()> {
+        let mut connection = Connection::open(db_path)?;
+        let transaction = connection.transaction()?;
+
+        // Resolve which player was player 1 and player 2 in the match
+        let (player_1, player_2): (String, String) = transaction.query_row(
+            "SELECT player_1_id, player_2_id FROM match WHERE id = ?1",
+            [request.game_id.to_string()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let reported = (request.players.0.to_string(), request.players.1.to_string());
+        let games_won = if reported == (player_1.clone(), player_2.clone()) {
+            request.games_won
+        } else if reported == (player_2.clone(), player_1.clone()) {
+            (request.games_won.1, request.games_won.0)
+        } else {
+            return Err(format!(
+                "Players {:?} do not match game {}",
+                request.players, request.game_id
+            )
+            .into());
+        };
+
         // Insert results into db
-        let connection = Connection::open(db_path)?;
-        connection.execute(
+        transaction.execute(
             "INSERT INTO match_results (id, player_1_score, player_2_score) VALUES (?1, ?2, ?3)",
-            (
-                &request.game_id.to_string(),
-                request.games_won.0,
-                request.games_won.1,
-            ),
+            (&request.game_id.to_string(), games_won.0, games_won.1),
         )?;
 
         // Update ELO
-        // TODO: impl
+        let ratings = (
+            Self::get_rating(&transaction, &player_1)?,
+            Self::get_rating(&transaction, &player_2)?,
+        );
+        let new_ratings = elo::update_ratings(ratings, elo::match_score(games_won));
+        for (player_id, rating) in [(&player_1, new_ratings.0), (&player_2, new_ratings.1)] {
+            transaction.execute(
+                "INSERT INTO players (id, rating, games_played) VALUES (?1, ?2, 1)
+                ON CONFLICT(id) DO UPDATE SET
+                    rating = excluded.rating,
+                    games_played = games_played + 1",
+                (player_id, rating),
+            )?;
+        }
+        transaction.commit()?;
+        info!(
+            "Updated ratings for game {}: {:?} -> {:?}",
+            request.game_id, ratings, new_ratings
+        );
 
         Ok(())
     }
 
+    // Look up a player's rating on a blocking thread, falling back to the default rating
+    async fn lookup_rating(db_url: String, player_id: Id) -> f64 {
+        let lookup = tokio::task::spawn_blocking(move || {
+            Connection::open(&db_url)
+                .map_err(|e| e.into())
+                .and_then(|connection| Self::get_rating(&connection, &player_id.to_string()))
+                .map_err(|e| e.to_string())
+        });
+        lookup
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|rating| rating)
+            .unwrap_or_else(|e| {
+                warn!("Failed to look up rating for {:?}: {}", player_id, e);
+                elo::DEFAULT_RATING
+            })
+    }
+
+    fn get_rating(connection: &Connection, player_id: &str) -> Result<f64> {
+        let rating = connection
+            .query_row(
+                "SELECT rating FROM players WHERE id = ?1",
+                [player_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(rating.unwrap_or(elo::DEFAULT_RATING))
+    }
+
+    // Place a game on a game server. The match is only recorded once a server has accepted it, so
+    // refused games don't leave matches behind.
     async fn create_game(
-        config: &MatchmakingConfig,
+        db_url: &str,
+        placement: &mut Placement,
         players: (Id, Id),
-    ) -> Result<CreateGameResponse> {
-        let game_id = &Id::new();
-        let games_to_win = 1u8;
-        // Create entry in database
-        let connection = Connection::open(&config.db_url)?;
+    ) -> std::result::Result<(Url, CreateGameResponse), PlaceGameError> {
+        let request = CreateGameRequest {
+            game_id: Id::new(),
+            players,
+            games_to_win: 1,
+        };
+        let placed = placement.create_game(&request).await?;
+        if let Err(e) = Self::record_match(db_url, &request) {
+            // The game is already running, so let it be played even if its result can't be rated
+            error!("Failed to record match {}: {}", request.game_id, e);
+        }
+        Ok(placed)
+    }
+
+    fn record_match(db_url: &str, request: &CreateGameRequest) -> Result<()> {
+        let connection = Connection::open(db_url)?;
         connection.execute(
             "INSERT INTO match (
                 id,
@@ -241,34 +746,21 @@ impl MatchmakingService {
                 ?1, ?2, ?3, ?4
             )",
             (
-                game_id.to_string(),
-                players.0.to_string(),
-                players.1.to_string(),
-                games_to_win,
+                request.game_id.to_string(),
+                request.players.0.to_string(),
+                request.players.1.to_string(),
+                request.games_to_win,
             ),
         )?;
-
-        // POST to game server to create a game threwd
-        let request = CreateGameRequest {
-            players: vec![players.0, players.1],
-            games_to_win,
-        };
-        let url = Url::parse(&config.game_server_url)?.join("create_game")?;
-        // TODO: retry logic?
-        Ok(Client::new()
-            .post(url)
-            .json(&request)
-            .send()
-            .await?
-            .json::<CreateGameResponse>()
-            .await?)
+        Ok(())
     }
 
+    // Requests only hold the state while building their response, which is sent after letting
+    // go of it
     async fn handle_message(
         state: Arc<Mutex<MatchmakingServiceState>>,
         message: Option<MatchmakingRequest>,
     ) {
-        let mut state = state.lock().await;
         debug!("msg: {:?}", message);
         let Some(message) = message else {
             info!("Got empty message");
@@ -280,31 +772,64 @@ impl MatchmakingService {
                 if sender.is_closed() {
                     warn!("Sender {:?} is closed!", player.id);
                 }
-                state.add_user(player);
-                let result = sender.send(ClientResponse::JoinedQueue).await;
-                if let Err(err) = result {
-                    error!("Got error when sending MatchmakingResponse: {}", err);
-                }
-            }
-            MatchmakingRequest::LeaveQueue(user_id) => match state.users_in_queue.get(&user_id) {
-                Some(_) => {
-                    let position = state
-                        .queue
-                        .iter()
-                        .enumerate()
-                        .find(|(_, user)| user.id == user_id);
-                    if let Some((position, user)) = position {
-                        info!("Removing user {:?} from queue", user.id);
-                        state.queue.remove(position);
+                // Looked up before taking the state, so the database never holds it up
+                let db_url = state.lock().await.config.db_url.clone();
+                let rating = Self::lookup_rating(db_url, player.id).await;
+                let response = {
+                    let mut state = state.lock().await;
+                    // Matching a player who's still seated would only be refused by the game
+                    // server
+                    if state.active_games.contains_key(&player.id) {
+                        warn!("User {:?} tried to queue while in a game", player.id);
+                        ClientResponse::error(
+                            "in_game",
+                            "Already in a game, use GetServer to rejoin it",
+                        )
+                    } else if state.add_user(player, rating) {
+                        ClientResponse::JoinedQueue
                     } else {
-                        warn!(
-                            "User {:?} was in users_in_queue but not in actual queue",
-                            user_id
-                        );
+                        ClientResponse::error("already_in_queue", "Already in the queue")
                     }
+                };
+                Self::reply(&sender, response);
+            }
+            MatchmakingRequest::LeaveQueue(user_id) => {
+                let mut state = state.lock().await;
+                if state.queue.remove(&user_id).is_some() || state.placing.remove(&user_id) {
+                    info!("Removed user {:?} from queue", user_id);
+                } else {
+                    debug!("User {:?} not in queue", user_id);
                 }
-                None => warn!("User {:?} not in queue", user_id),
-            },
+            }
+            MatchmakingRequest::GetServer(player) => {
+                let response = {
+                    let state = state.lock().await;
+                    match state.active_games.get(&player.id) {
+                        Some(game) => ClientResponse::JoinServer {
+                            game_id: game.game_id,
+                            server_address: game.server_address.clone(),
+                            ticket: state.join_ticket(game.game_id, player.id),
+                        },
+                        None => ClientResponse::NoActiveGame,
+                    }
+                };
+                Self::reply(&player.sender, response);
+            }
+            MatchmakingRequest::Ping(player) => {
+                let response = {
+                    let state = state.lock().await;
+                    match (
+                        state.queue.get(&player.id),
+                        state.queue.position(&player.id),
+                    ) {
+                        (Some(queued), Some(position)) => {
+                            state.queue_ping(queued, position, Instant::now())
+                        }
+                        _ => ClientResponse::NotInQueue,
+                    }
+                };
+                Self::reply(&player.sender, response);
+            }
         };
     }
 }
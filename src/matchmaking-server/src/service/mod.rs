@@ -1,2 +1,5 @@
+pub mod elo;
+pub mod game_servers;
 pub mod matchmaking;
+pub mod queue;
 pub mod queue_socket;
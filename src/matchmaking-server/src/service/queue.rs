@@ -0,0 +1,181 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use common::model::messages::Id;
+
+use crate::model::messages::Player;
+
+use super::elo;
+
+// A player waiting in the queue, along with what's needed to pair them
+#[derive(Debug, Clone)]
+pub struct QueuedPlayer {
+    pub player: Player,
+    pub rating: f64,
+    pub joined_at: Instant,
+    // Not paired until then, after a game server refused a game with them
+    pub held_until: Option<Instant>,
+    // Position in the queue. Lower tickets are served first.
+    ticket: i64,
+}
+
+impl QueuedPlayer {
+    pub fn id(&self) -> Id {
+        self.player.id
+    }
+
+    pub fn is_held(&self, now: Instant) -> bool {
+        self.held_until.is_some_and(|until| now < until)
+    }
+
+    // Whether both players accept each other's rating given how long they've waited
+    pub fn accepts(&self, other: &QueuedPlayer, now: Instant) -> bool {
+        let window = f64::min(
+            elo::rating_window(now - self.joined_at),
+            elo::rating_window(now - other.joined_at),
+        );
+        (self.rating - other.rating).abs() <= window
+    }
+}
+
+// Players waiting for a match, indexed by id. Order is kept by each player's ticket rather than
+// their position in a list, so removing a player is O(1) and membership can't drift from order.
+#[derive(Debug, Default)]
+pub struct PlayerQueue {
+    players: HashMap<Id, QueuedPlayer>,
+    // Next ticket at the back of the queue, and the last ticket handed out at the front
+    next_back: i64,
+    next_front: i64,
+}
+
+impl PlayerQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.players.contains_key(id)
+    }
+
+    // Add a player behind everyone already waiting. Returns false if they were already queued.
+    pub fn push_back(&mut self, player: Player, rating: f64) -> bool {
+        let ticket = self.next_back;
+        self.next_back += 1;
+        self.insert(player, rating, ticket)
+    }
+
+    // Add a player ahead of everyone already waiting. Returns false if they were already queued.
+    pub fn push_front(&mut self, player: Player, rating: f64) -> bool {
+        self.next_front -= 1;
+        let ticket = self.next_front;
+        self.insert(player, rating, ticket)
+    }
+
+    // Put a player who was taken out of the queue back in front, keeping how long they've waited
+    pub fn requeue_front(&mut self, mut player: QueuedPlayer) -> bool {
+        if self.contains(&player.id()) {
+            return false;
+        }
+        self.next_front -= 1;
+        player.ticket = self.next_front;
+        self.players.insert(player.id(), player);
+        true
+    }
+
+    pub fn get(&self, id: &Id) -> Option<&QueuedPlayer> {
+        self.players.get(id)
+    }
+
+    // 1-based place in line, counting everyone who will be considered before this player
+    pub fn position(&self, id: &Id) -> Option<usize> {
+        let ticket = self.players.get(id)?.ticket;
+        Some(
+            self.players
+                .values()
+                .filter(|player| player.ticket <= ticket)
+                .count(),
+        )
+    }
+
+    pub fn remove(&mut self, id: &Id) -> Option<QueuedPlayer> {
+        self.players.remove(id)
+    }
+
+    // Queued players, first in line first
+    pub fn in_order(&self) -> Vec<&QueuedPlayer> {
+        let mut players: Vec<&QueuedPlayer> = self.players.values().collect();
+        players.sort_by_key(|player| player.ticket);
+        players
+    }
+
+    fn insert(&mut self, player: Player, rating: f64, ticket: i64) -> bool {
+        if self.contains(&player.id) {
+            return false;
+        }
+        self.players.insert(
+            player.id,
+            QueuedPlayer {
+                player,
+                rating,
+                joined_at: Instant::now(),
+                held_until: None,
+                ticket,
+            },
+        );
+        true
+    }
+}
+
+// How long matches are remembered when estimating throughput
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(300);
+
+// Recent matches, used to estimate how long a queued player still has to wait
+#[derive(Debug)]
+pub struct Throughput {
+    started_at: Instant,
+    matches: VecDeque<Instant>,
+}
+
+impl Throughput {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            matches: VecDeque::new(),
+        }
+    }
+
+    pub fn record_match(&mut self, now: Instant) {
+        self.matches.push_back(now);
+        self.prune(now);
+    }
+
+    // Expected wait for the player at `position`, assuming players keep leaving the queue as fast
+    // as they did over the window. None until there's been a match to go on.
+    pub fn estimated_wait(&self, position: usize, now: Instant) -> Option<Duration> {
+        let recent_matches = self
+            .matches
+            .iter()
+            .filter(|matched_at| now - **matched_at <= THROUGHPUT_WINDOW)
+            .count();
+        if recent_matches == 0 {
+            return None;
+        }
+        // Don't count time before the server started as time without matches
+        let window = THROUGHPUT_WINDOW.min(now - self.started_at);
+        let players_per_second = (2 * recent_matches) as f64 / window.as_secs_f64().max(1.0);
+        Some(Duration::from_secs_f64(
+            position as f64 / players_per_second,
+        ))
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(oldest) = self.matches.front() {
+            if now - *oldest <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.matches.pop_front();
+        }
+    }
+}
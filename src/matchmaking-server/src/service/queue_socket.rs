@@ -1,7 +1,8 @@
-use std::net::Ipv6Addr;
-
 use axum::async_trait;
-use common::{model::messages::Id, websocket::WebsocketHandler};
+use common::{
+    model::messages::Id,
+    websocket::{WebsocketError, WebsocketHandler},
+};
 use tokio::sync::mpsc::Sender;
 use tracing::{debug, warn};
 
@@ -16,7 +17,7 @@ impl WebsocketHandler<ClientRequest, ClientResponse, MatchmakingRequest> for Que
         request: ClientRequest,
         to_user_sender: Sender<ClientResponse>,
         mm_sender: Sender<MatchmakingRequest>,
-    ) -> Option<ClientResponse> {
+    ) -> Result<Option<ClientResponse>, WebsocketError> {
         match request {
             ClientRequest::JoinQueue => {
                 // Tell matchmaking to add user to the queue
@@ -25,27 +26,49 @@ impl WebsocketHandler<ClientRequest, ClientResponse, MatchmakingRequest> for Que
                     sender: to_user_sender.clone(),
                 });
                 debug!("Send mm {:?}", mm_request);
-                mm_sender
-                    .send(mm_request)
-                    .await
-                    .map_err(|e| warn!("got err {:?}", e))
-                    .expect("Failed to send message to matchmaker");
-                None
+                Self::forward(&mm_sender, mm_request).await?;
+                Ok(None)
+            }
+            ClientRequest::LeaveQueue => {
+                Self::forward(&mm_sender, MatchmakingRequest::LeaveQueue(user_id)).await?;
+                Ok(Some(ClientResponse::LeftQueue))
+            }
+            ClientRequest::Ping => {
+                // Only matchmaking knows how long the user has been waiting
+                Self::forward(
+                    &mm_sender,
+                    MatchmakingRequest::Ping(Player {
+                        id: user_id,
+                        sender: to_user_sender.clone(),
+                    }),
+                )
+                .await?;
+                Ok(None)
+            }
+            ClientRequest::GetServer => {
+                // Matchmaking knows which game the user is in, and replies on their sender
+                Self::forward(
+                    &mm_sender,
+                    MatchmakingRequest::GetServer(Player {
+                        id: user_id,
+                        sender: to_user_sender.clone(),
+                    }),
+                )
+                .await?;
+                Ok(None)
             }
-            ClientRequest::Ping => Some(ClientResponse::QueuePing { time_elapsed: 0u32 }),
-            ClientRequest::GetServer => Some(ClientResponse::JoinServer {
-                server_ip: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
-            }),
         }
     }
 
+    async fn on_disconnect(user_id: Id, mm_sender: Sender<MatchmakingRequest>) {
+        // Don't leave a ghost in the queue that could be matched. Nothing's left to close.
+        let _ = Self::forward(&mm_sender, MatchmakingRequest::LeaveQueue(user_id)).await;
+    }
+
     fn drop_after_send(response: ClientResponse) -> bool {
         matches!(
             response,
-            ClientResponse::MatchFound {
-                game_id: _,
-                server_address: _,
-            } | ClientResponse::JoinServer { server_ip: _ }
+            ClientResponse::MatchFound { .. } | ClientResponse::JoinServer { .. }
         )
     }
 }
@@ -54,6 +77,18 @@ impl QueueSocket {
     pub fn new() -> Self {
         Self {}
     }
+
+    // Pass a request on to matchmaking, which only stops listening when the server is going
+    // down. The caller ends the session on failure.
+    async fn forward(
+        mm_sender: &Sender<MatchmakingRequest>,
+        request: MatchmakingRequest,
+    ) -> Result<(), WebsocketError> {
+        mm_sender.send(request).await.map_err(|e| {
+            warn!("Failed to send {:?} to matchmaking", e.0);
+            WebsocketError::Internal("Matchmaking is unavailable".to_owned())
+        })
+    }
 }
 
 impl Default for QueueSocket {
@@ -1,28 +1,37 @@
 /* eslint-disable @typescript-eslint/no-unused-vars */
 "use client";
 
-import { useState } from "react";
+import { useEffect, useState } from "react";
 import Game from "./game/game";
 import Queue from "./queue/queue";
 import { Result } from "./game/model";
-
-type ClientProps = {
-  id: string;
-};
+import { createSession, Session } from "./shared/session";
 
 type ClientState =
   | { screen: "queue" }
-  | { screen: "game"; serverAddress: string };
+  | { screen: "game"; serverAddress: string; ticket: string };
 
-export default function Client({ id: userId }: ClientProps) {
+export default function Client() {
+  const [session, setSession] = useState<Session | null>(null);
   const [state, setState] = useState<ClientState>({ screen: "queue" });
   const [matchResult, setMatchResult] = useState<Result | null>(null);
   const [record, setRecord] = useState({ wins: 0, losses: 0 });
 
-  function joinGame(serverAddress: string) {
-    setState({ screen: "game", serverAddress });
+  // Sockets identify us with a token from matchmaking
+  useEffect(() => {
+    createSession()
+      .then(setSession)
+      .catch((error) => console.log(error));
+  }, []);
+
+  function joinGame(serverAddress: string, ticket: string) {
+    setState({ screen: "game", serverAddress, ticket });
   }
 
+  const cancelGameAction = () => {
+    setState({ screen: "queue" });
+  };
+
   const endGameAction = (result: Result) => {
     setState({ screen: "queue" });
     setMatchResult(result);
@@ -46,9 +55,12 @@ export default function Client({ id: userId }: ClientProps) {
       <div className="absolute top-0 right-0 text-sm font-semibold">
         Record: {record.wins}W - {record.losses}L
       </div>
-      {state.screen == "queue" && <Queue userId={userId} joinGame={joinGame} />}
-      {state.screen == "game" && (
-        <Game userId={userId} serverAddress={state.serverAddress} endGameAction={endGameAction}/>
+      {session == null && <span>Starting session...</span>}
+      {session != null && state.screen == "queue" && (
+        <Queue token={session.token} joinGame={joinGame} />
+      )}
+      {session != null && state.screen == "game" && (
+        <Game token={session.token} serverAddress={state.serverAddress} ticket={state.ticket} endGameAction={endGameAction} cancelGameAction={cancelGameAction}/>
       )}
     </div>
   );
@@ -67,7 +67,7 @@ export class ClientList extends Component<ClientListProps, ClientListState> {
               <h2 className="text-lg font-semibold mb-2 text-gray-700">
                 Client {index}
               </h2>
-              <Client id={id} />
+              <Client />
             </div>
           ))}
         </div>
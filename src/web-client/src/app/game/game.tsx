@@ -1,25 +1,30 @@
 /* eslint-disable @typescript-eslint/no-unused-vars */
 "use client";
-import React, { useEffect, useState } from "react";
+import React, { useEffect, useRef, useState } from "react";
 import useWebSocket, { ConnectionStatus } from "../hooks/socket";
 import { GameRequest, GameResponse } from "./requests";
 import { Move, Result } from "./model";
 import { match } from "ts-pattern";
 import { Trophy } from "lucide-react";
+import { getServer } from "../queue/server";
 
 // Component
 export type GameComponentProps = {
-  userId: string;
+  token: string;
   serverAddress: string;
+  // Single-use ticket from matchmaking for our seat in the game
+  ticket: string;
   endGameAction: (matchResult: Result) => void;
+  cancelGameAction: () => void;
 };
 
 type GameState =
   | { type: "Connecting" }
   | { type: "Connected" }
-  | { type: "PendingMove" }
+  | { type: "PendingMove"; round: number }
   | { type: "MoveSent" }
   | { type: "RoundResult"; result: Result; other_move: Move }
+  | { type: "MoveTimeout"; missed: boolean }
   | {
       type: "MatchResult";
       result: Result;
@@ -28,21 +33,34 @@ type GameState =
     };
 
 export default function Game({
-  userId,
+  token,
   serverAddress,
+  ticket,
   endGameAction,
+  cancelGameAction,
 }: GameComponentProps) {
-  const socket = useWebSocket<GameRequest, GameResponse>(userId);
+  const socket = useWebSocket<GameRequest, GameResponse>(token);
   const [gameState, setGameState] = useState<GameState>({ type: "Connecting" });
   const [myScore, setMyScore] = useState<number>(0);
   const [opponentScore, setOpponentScore] = useState<number>(0);
+  // Tickets are single-use, so only the first connection can use the one we were given
+  const ticketUsed = useRef(false);
 
   // Create socket listener
   useEffect(() => {
-    const onOpenRequestProvider: () => GameRequest = () => {
-      return {
-        type: "JoinGame",
-      };
+    const onOpenRequestProvider = async (): Promise<GameRequest | null> => {
+      if (!ticketUsed.current) {
+        ticketUsed.current = true;
+        return { type: "JoinGame", ticket };
+      }
+      // Reconnecting, so get a fresh ticket for our seat
+      const game = await getServer(token);
+      if (game == null) {
+        console.log("Game is over, nothing to rejoin");
+        cancelGameAction();
+        return null;
+      }
+      return { type: "JoinGame", ticket: game.ticket };
     };
 
     // Message handler
@@ -52,9 +70,21 @@ export default function Game({
         .with({ type: "GameJoined" }, () => {
           setGameState({ type: "Connected" });
         })
-        .with({ type: "PendingMove" }, () => {
-          setGameState({ type: "PendingMove" });
+        .with({ type: "PendingMove" }, ({ round, wins, opponent_wins }) => {
+          setGameState({ type: "PendingMove", round });
+          setMyScore(wins);
+          setOpponentScore(opponent_wins);
         })
+        .with(
+          { type: "Reconnected" },
+          ({ round, wins, opponent_wins, move_pending }) => {
+            setGameState(
+              move_pending ? { type: "PendingMove", round } : { type: "MoveSent" },
+            );
+            setMyScore(wins);
+            setOpponentScore(opponent_wins);
+          },
+        )
         .with({ type: "RoundResult" }, ({ result, other_move }) => {
           setGameState({ type: "RoundResult", result, other_move });
           if (result === Result.Win) {
@@ -63,16 +93,48 @@ export default function Game({
             setOpponentScore((prev) => prev + 1);
           }
         })
+        .with({ type: "MoveTimeout" }, ({ result, missed }) => {
+          setGameState({ type: "MoveTimeout", missed });
+          if (result === Result.Win) {
+            setMyScore((prev) => prev + 1);
+          } else if (result === Result.Loss) {
+            setOpponentScore((prev) => prev + 1);
+          }
+        })
         .with({ type: "MatchResult" }, ({ result, wins, total }) => {
           setGameState({ type: "MatchResult", result, wins, total });
           endGameAction(result);
         })
+        .with({ type: "GameCancelled" }, ({ reason }) => {
+          console.log("Game cancelled: " + reason);
+          cancelGameAction();
+        })
+        .with({ type: "JoinRejected" }, ({ reason }) => {
+          console.log("Couldn't join game: " + reason);
+          cancelGameAction();
+        })
+        .with({ type: "Error" }, ({ code, message }) => {
+          console.log("Game server refused request (" + code + "): " + message);
+          match(code)
+            // Our move was dropped, so let the player pick again
+            .with("game_not_started", () => setGameState({ type: "Connected" }))
+            .with("game_over", "not_in_game", () => cancelGameAction())
+            .otherwise(() => {});
+        })
         .otherwise((val) => console.log("TODO: " + val));
     };
     if (socket.connectionStatus == ConnectionStatus.Off) {
       socket.connect(serverAddress, onOpenRequestProvider, messageHandler);
     }
-  }, [socket, serverAddress, gameState, endGameAction]);
+  }, [
+    socket,
+    token,
+    serverAddress,
+    ticket,
+    gameState,
+    endGameAction,
+    cancelGameAction,
+  ]);
 
   const makeMove = (move: Move) => {
     socket.send({ type: "Move", value: move });
@@ -87,8 +149,10 @@ export default function Game({
       .with({ type: "Connected" }, () => (
         <div className="text-green-500">Connected! Ready to play.</div>
       ))
-      .with({ type: "PendingMove" }, () => (
-        <div className="text-blue-500">Your turn! Make a move.</div>
+      .with({ type: "PendingMove" }, ({ round }) => (
+        <div className="text-blue-500">
+          Round {round}: your turn! Make a move.
+        </div>
       ))
       .with({ type: "MoveSent" }, () => (
         <div className="text-gray-500">Move sent, waiting for opponent...</div>
@@ -108,6 +172,13 @@ export default function Game({
           </div>
         </div>
       ))
+      .with({ type: "MoveTimeout" }, ({ missed }) => (
+        <div className="text-red-500">
+          {missed
+            ? "You ran out of time to move."
+            : "Your opponent ran out of time to move."}
+        </div>
+      ))
       .with({ type: "MatchResult" }, ({ result, wins, total }) => (
         <div className="p-4 border rounded bg-gray-100">
           <div className="flex items-center justify-center mb-4">
@@ -1,11 +1,22 @@
 import { Move, Result } from "./model";
 
 export type GameRequest =
-    | { type: "JoinGame" }
+    | { type: "JoinGame"; ticket: string }
     | { type: "Move"; value: Move };
 
 export type GameResponse =
     | { type: "GameJoined" }
-    | { type: "PendingMove" }
+    | { type: "PendingMove"; round: number; wins: number; opponent_wins: number }
     | { type: "RoundResult"; result: Result; other_move: Move }
-    | { type: "MatchResult"; result: Result, wins: number, total: number };
+    | { type: "MoveTimeout"; result: Result; missed: boolean; opponent_missed: boolean }
+    | { type: "MatchResult"; result: Result, wins: number, total: number }
+    | { type: "GameCancelled"; reason: string }
+    | { type: "JoinRejected"; reason: string }
+    | { type: "Error"; code: string; message: string }
+    | {
+          type: "Reconnected";
+          round: number;
+          wins: number;
+          opponent_wins: number;
+          move_pending: boolean;
+      };
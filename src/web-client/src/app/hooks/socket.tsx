@@ -14,7 +14,8 @@ export type SocketHook<RQ, RS> = {
   // Methods
   connect: (
     url: string,
-    onOpenRequestProvider: () => RQ,
+    // Called every time the socket opens, including on reconnects
+    onOpenRequestProvider: () => RQ | null | Promise<RQ | null>,
     onMessage: (message: RS) => void,
   ) => void;
   send: (msg: RQ) => void;
@@ -22,11 +23,14 @@ export type SocketHook<RQ, RS> = {
 };
 
 type OpenSocketRequest = {
-  userId: string;
+  token: string;
 };
 
+// Close code the server uses when it doesn't accept our session token
+const INVALID_TOKEN_CLOSE_CODE = 4001;
+
 export default function useWebSocket<RQ, RS>(
-  userId: string,
+  token: string,
 ): SocketHook<RQ, RS> {
   const [connectionStatus, setConnectionStatus] = useState<ConnectionStatus>(
     ConnectionStatus.Off,
@@ -41,7 +45,7 @@ export default function useWebSocket<RQ, RS>(
 
   const connectWebSocket = (
     url: string,
-    onOpenRequestProvider: () => RQ | null,
+    onOpenRequestProvider: () => RQ | null | Promise<RQ | null>,
     onMessage: (response: RS) => void,
   ) => {
     const newSocket = new WebSocket(url);
@@ -50,14 +54,20 @@ export default function useWebSocket<RQ, RS>(
     // TODO: Why does this fire twice?
     newSocket.onopen = () => {
       // Identify user according to protocol
-      newSocket.send(JSON.stringify({ userId: userId } as OpenSocketRequest));
+      newSocket.send(JSON.stringify({ token: token } as OpenSocketRequest));
 
       // Call on-open request provider, send if we get something.
       setConnectionStatus(ConnectionStatus.Connected);
-      const request = onOpenRequestProvider();
-      if (request != null) {
-        send(newSocket, request);
-      }
+      Promise.resolve(onOpenRequestProvider())
+        .then((request) => {
+          if (request != null && newSocket.readyState === WebSocket.OPEN) {
+            send(newSocket, request);
+          }
+        })
+        .catch((error) => {
+          console.log("Failed to get request for new socket: ", error);
+          newSocket.close(1000, "Nothing to send");
+        });
     };
 
     newSocket.onerror = (event: Event) => {
@@ -78,6 +88,13 @@ export default function useWebSocket<RQ, RS>(
         return;
       }
 
+      // Reconnecting with the same token won't help
+      if (event.code === INVALID_TOKEN_CLOSE_CODE) {
+        console.log("Session token rejected: " + event.reason);
+        setConnectionStatus(ConnectionStatus.Failed);
+        return;
+      }
+
       // Otherwise, try to reconnect
       // TODO: this should be specified by the hook user
       setTimeout(() => {
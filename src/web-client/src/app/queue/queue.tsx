@@ -3,19 +3,29 @@ import useWebSocket, { ConnectionStatus } from "../hooks/socket";
 import { match } from "ts-pattern";
 import { MatchmakingRequest } from "./requests";
 import { MatchmakingResponse } from "./responses";
+import { MATCHMAKING_SOCKET_URL } from "./server";
 
 type QueueProps = {
-  userId: string;
-  joinGame: (serverAddress: string) => void;
+  token: string;
+  joinGame: (serverAddress: string, ticket: string) => void;
 };
 
+type QueueStatus =
+  | {
+      type: "Waiting";
+      timeElapsed: number;
+      position: number;
+      estimatedWait: number | null;
+    }
+  | { type: "ServersUnavailable" };
+
 type QueueState =
-  | { type: "InQueue" }
+  | { type: "InQueue"; status?: QueueStatus }
   | { type: "Connecting" }
   | { type: "NotInQueue" };
 
-export default function Queue({ userId, joinGame }: QueueProps) {
-  const queue = useWebSocket<MatchmakingRequest, MatchmakingResponse>(userId);
+export default function Queue({ token, joinGame }: QueueProps) {
+  const queue = useWebSocket<MatchmakingRequest, MatchmakingResponse>(token);
   const [queueState, setQueueState] = useState<QueueState>({
     type: "NotInQueue",
   });
@@ -25,9 +35,46 @@ export default function Queue({ userId, joinGame }: QueueProps) {
       .with({ type: "JoinedQueue" }, ({}) => {
         setQueueState({ type: "InQueue" });
       })
-      .with({ type: "MatchFound" }, ({ server_address }) => {
+      .with(
+        { type: "QueuePing" },
+        ({ time_elapsed, position, estimated_wait }) => {
+          setQueueState({
+            type: "InQueue",
+            status: {
+              type: "Waiting",
+              timeElapsed: time_elapsed,
+              position,
+              estimatedWait: estimated_wait,
+            },
+          });
+        },
+      )
+      .with({ type: "ServersUnavailable" }, () => {
+        setQueueState({
+          type: "InQueue",
+          status: { type: "ServersUnavailable" },
+        });
+      })
+      .with({ type: "MatchFound" }, ({ server_address, ticket }) => {
         setQueueState({ type: "NotInQueue" });
-        joinGame(server_address);
+        joinGame(server_address, ticket);
+      })
+      .with({ type: "JoinServer" }, ({ server_address, ticket }) => {
+        setQueueState({ type: "NotInQueue" });
+        joinGame(server_address, ticket);
+      })
+      .with({ type: "Error" }, ({ code, message }) => {
+        console.log("Matchmaking refused request (" + code + "): " + message);
+        match(code)
+          // Our earlier JoinQueue went through, so we're still queued
+          .with("already_in_queue", () =>
+            setQueueState((previous) =>
+              previous.type === "InQueue" ? previous : { type: "InQueue" },
+            ),
+          )
+          // Still seated in a game, so go back to it instead
+          .with("in_game", () => queue.send({ type: "GetServer" }))
+          .otherwise(() => {});
       })
       .otherwise((msg) => {
         console.log("Unexpected queue message: " + msg);
@@ -38,7 +85,7 @@ export default function Queue({ userId, joinGame }: QueueProps) {
   function joinQueue() {
     setQueueState({ type: "Connecting" });
     queue.connect(
-      "ws://localhost:3001",
+      MATCHMAKING_SOCKET_URL,
       () => {
         return { type: "JoinQueue" } as MatchmakingRequest;
       },
@@ -48,6 +95,7 @@ export default function Queue({ userId, joinGame }: QueueProps) {
 
   function leaveQueue() {
     if (queue) {
+      queue.send({ type: "LeaveQueue" });
       queue.close();
       setMessages([]);
       setQueueState({ type: "NotInQueue" });
@@ -77,8 +125,22 @@ export default function Queue({ userId, joinGame }: QueueProps) {
       </span>
     );
   }
-  function timer() {
-    return <>InQueue</>;
+  function timer(status?: QueueStatus) {
+    if (!status) {
+      return <>InQueue</>;
+    }
+    if (status.type === "ServersUnavailable") {
+      return <>No game servers available, waiting for one to come back</>;
+    }
+    return (
+      <div className="flex flex-col items-center">
+        <span>In queue for {status.timeElapsed}s</span>
+        <span>Position {status.position}</span>
+        {status.estimatedWait !== null && (
+          <span>Estimated wait {status.estimatedWait}s</span>
+        )}
+      </div>
+    );
   }
 
   const QueueStateView = () => {
@@ -92,9 +154,9 @@ export default function Queue({ userId, joinGame }: QueueProps) {
         </button>
       ))
       .with({ type: "Connecting" }, () => spinner())
-      .with({ type: "InQueue" }, () => (
+      .with({ type: "InQueue" }, ({ status }) => (
         <div className="flex flex-col items-center gap-4">
-          {timer()}
+          {timer(status)}
           <button
             className="px-6 py-2 rounded-md bg-red-50 text-red-700 border-2 border-red-200 hover:bg-red-100 transition-colors duration-200 font-medium shadow-sm"
             onClick={leaveQueue}
@@ -1,4 +1,6 @@
 // Requests
 export type MatchmakingRequest =
     | { type: "JoinQueue" }
+    | { type: "LeaveQueue" }
     | { type: "Ping" }
+    | { type: "GetServer" }
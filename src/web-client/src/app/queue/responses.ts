@@ -3,7 +3,26 @@ export type MatchmakingResponse =
     | { type: "Connected"; userId: string }
     | { type: "AckJoinQueue" }
     | { type: "JoinedQueue" }
-    | { type: "QueuePing"; timeElapsed: number }
-    | { type: "MatchFound"; game_id: string; server_address: string }
-    | { type: "JoinServer"; server_address: string }
-    | { type: "Error"; message: string };
+    | { type: "LeftQueue" }
+    | {
+          type: "QueuePing";
+          time_elapsed: number;
+          position: number;
+          estimated_wait: number | null;
+      }
+    | { type: "NotInQueue" }
+    | { type: "ServersUnavailable" }
+    | {
+          type: "MatchFound";
+          game_id: string;
+          server_address: string;
+          ticket: string;
+      }
+    | {
+          type: "JoinServer";
+          game_id: string;
+          server_address: string;
+          ticket: string;
+      }
+    | { type: "NoActiveGame" }
+    | { type: "Error"; code: string; message: string };
@@ -0,0 +1,45 @@
+import { MatchmakingRequest } from "./requests";
+import { MatchmakingResponse } from "./responses";
+
+export const MATCHMAKING_SOCKET_URL = "ws://localhost:3001";
+
+export type ActiveGame = {
+  server_address: string;
+  ticket: string;
+};
+
+// Ask matchmaking for the game we're in, with a fresh ticket to rejoin it. Join tickets are
+// single-use, so this is needed every time we reconnect to a game server. Resolves to null if
+// we're no longer in a game.
+export function getServer(token: string): Promise<ActiveGame | null> {
+  return new Promise((resolve, reject) => {
+    const socket = new WebSocket(MATCHMAKING_SOCKET_URL);
+    const send = (request: MatchmakingRequest) =>
+      socket.send(JSON.stringify(request));
+    socket.onopen = () => {
+      socket.send(JSON.stringify({ token }));
+      send({ type: "GetServer" });
+    };
+    socket.onmessage = (event) => {
+      const message = JSON.parse(event.data) as MatchmakingResponse;
+      if (message.type === "JoinServer") {
+        resolve({
+          server_address: message.server_address,
+          ticket: message.ticket,
+        });
+      } else if (message.type === "NoActiveGame") {
+        resolve(null);
+      } else if (message.type === "Error") {
+        reject(
+          new Error("GetServer failed (" + message.code + "): " + message.message),
+        );
+      } else {
+        return;
+      }
+      socket.close(1000, "Got server");
+    };
+    socket.onerror = () => reject(new Error("Couldn't reach matchmaking"));
+    // No effect once we've got an answer
+    socket.onclose = () => reject(new Error("Matchmaking closed the socket"));
+  });
+}
@@ -0,0 +1,19 @@
+const MATCHMAKING_URL = "http://localhost:8081";
+
+export type Session = {
+  user_id: string;
+  token: string;
+};
+
+// Get a session token from matchmaking. Passing an existing token renews it for the same user.
+export async function createSession(token?: string): Promise<Session> {
+  const response = await fetch(MATCHMAKING_URL + "/session", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ token: token ?? null }),
+  });
+  if (!response.ok) {
+    throw new Error("Failed to create session: " + response.status);
+  }
+  return (await response.json()) as Session;
+}